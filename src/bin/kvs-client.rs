@@ -4,16 +4,81 @@ use kvs::{
     error::KvsError,
     Result,
 };
-use std::{net::SocketAddr, process::exit};
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    process::exit,
+    time::Duration,
+};
 use structopt::StructOpt;
 #[derive(StructOpt)]
 struct Config {
     #[structopt(subcommand)]
-    request: Request,
+    cmd: Cmd,
     #[structopt(long, global = true, default_value = "127.0.0.1:4000")]
     addr: SocketAddr,
 }
 
+#[derive(StructOpt)]
+enum Cmd {
+    Set {
+        key: String,
+        value: String,
+        /// remove the key after this many seconds
+        #[structopt(long)]
+        ttl: Option<u64>,
+    },
+    Get {
+        key: String,
+    },
+    #[structopt(alias = "rm")] // rm is the subcmd used by test
+    Remove {
+        key: String,
+    },
+    /// set the key to `--new` only if it holds `--expected`, a missing flag means no value
+    Cas {
+        key: String,
+        #[structopt(long)]
+        expected: Option<String>,
+        #[structopt(long)]
+        new: Option<String>,
+    },
+    /// report the keys and the data files of the engine
+    Stats,
+    /// write a checkpoint of the db into a missing or empty dir on the server,
+    /// relative to the `--backup-dir` of the server and without `..`
+    Backup {
+        #[structopt(parse(from_os_str))]
+        dest: PathBuf,
+    },
+}
+
+impl From<Cmd> for Request {
+    fn from(cmd: Cmd) -> Self {
+        match cmd {
+            Cmd::Set { key, value, ttl } => Request::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                ttl: ttl.map(Duration::from_secs),
+            },
+            Cmd::Get { key } => Request::Get {
+                key: key.into_bytes(),
+            },
+            Cmd::Remove { key } => Request::Remove {
+                key: key.into_bytes(),
+            },
+            Cmd::Cas { key, expected, new } => Request::CompareAndSwap {
+                key: key.into_bytes(),
+                expected: expected.map(String::into_bytes),
+                new: new.map(String::into_bytes),
+            },
+            Cmd::Stats => Request::Stats,
+            Cmd::Backup { dest } => Request::Backup { dest },
+        }
+    }
+}
+
 fn main() {
     if let Err(e) = run_app() {
         if let KvsError::KeyNotFound { key: _ } = e {
@@ -26,15 +91,40 @@ fn main() {
 fn run_app() -> Result<()> {
     let cfg = Config::from_args();
     let mut client = KvsClient::connect(cfg.addr)?;
-    match client.send_request(&cfg.request)? {
+    match client.send_request(&cfg.cmd.into())? {
         Response::Set(_) => (),
-        Response::Get(r) => match r.map_err(|s| KvsError::Inner(s))? {
-            Some(value) => println!("{value}"),
-            None => println!("Key not found"),
-        },
-        Response::Remove(result) => {
-            return result.map_err(|e| KvsError::KeyNotFound { key: e })
+        Response::Get(r) => print_value(r.map_err(KvsError::Inner)?)?,
+        Response::Remove(result) => return result.map_err(|e| KvsError::KeyNotFound { key: e }),
+        Response::Batch(result)
+        | Response::Begin(result)
+        | Response::Abort(result)
+        | Response::Backup(result) => result.map_err(KvsError::Inner)?,
+        Response::Commit(result) => {
+            if !result.map_err(KvsError::Inner)? {
+                return Err(KvsError::TransactionError("the transaction conflicted"));
+            }
+        }
+        // print the current value on mismatch
+        Response::CompareAndSwap(result) => {
+            if let Err(e) = result.map_err(KvsError::Inner)? {
+                print_value(e.current)?;
+                return Err(KvsError::CommandError("the value is not the expected one"));
+            }
+        }
+        Response::Stats(result) => print!("{}", result.map_err(KvsError::Inner)?),
+    }
+    Ok(())
+}
+
+// the value may not be UTF-8, print it as it is
+fn print_value(value: Option<Vec<u8>>) -> Result<()> {
+    match value {
+        Some(value) => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&value)?;
+            stdout.write_all(b"\n")?;
         }
+        None => println!("Key not found"),
     }
     Ok(())
 }
@@ -1,11 +1,18 @@
 use kvs::{
+    cli::KvStoreFlags,
     engine::sled::SledKvsEngine,
     error::KvsError,
-    server::{KvsEngineSel, KvsServer},
-    KvStore, Result,
+    server::{KvsEngineSel, KvsServer, ENGINE_MARKER},
+    KvStore, KvsEngine, Result,
 };
 use slog::{error, info, o, Drain, Logger};
-use std::{env::current_dir, fs, io, net::SocketAddr, path::Path, process::exit};
+use std::{
+    env::current_dir,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::exit,
+};
 use structopt::{clap::crate_version, StructOpt};
 
 #[derive(StructOpt)]
@@ -14,6 +21,13 @@ struct Config {
     addr: SocketAddr,
     #[structopt(long, global = true)]
     engine: Option<KvsEngineSel>,
+    /// the dir clients write backups under, out of the dir of the db.
+    /// backups are refused without it
+    #[structopt(long, parse(from_os_str))]
+    backup_dir: Option<PathBuf>,
+    /// only used by the kvs engine
+    #[structopt(flatten)]
+    kvstore: KvStoreFlags,
 }
 
 fn main() {
@@ -23,6 +37,8 @@ fn main() {
     let logger = slog::Logger::root(drain, o!());
     if let Err(e) = run_app(&logger) {
         error!(logger, "{e}");
+        // `exit` runs no destructor, so flush the async drain first
+        drop(logger);
         exit(1);
     }
 }
@@ -33,7 +49,7 @@ fn run_app(log: &Logger) -> Result<()> {
     info!(log, "version: {}", crate_version!());
     let path = current_dir()?;
     let engine: KvsEngineSel = {
-        let path = path.join("00engine");
+        let path = path.join(ENGINE_MARKER);
         let e_cli = cfg.engine;
         match current_engine(&path)? {
             Some(e_disk) => {
@@ -54,12 +70,53 @@ fn run_app(log: &Logger) -> Result<()> {
         }
     };
     info!(log, "using storage engine: {engine}");
-    let server = match engine {
-        KvsEngineSel::KvStore => KvsServer::new(KvStore::open(&path)?, log),
-        KvsEngineSel::SledKvsEngine => KvsServer::new(SledKvsEngine::open(&path)?, log),
-    };
-    info!(log, "server listening on socket: {}", cfg.addr);
-    server.run(cfg.addr)
+    let backup_dir = cfg
+        .backup_dir
+        .map(|dir| backup_dir(&path, &dir))
+        .transpose()?;
+    match engine {
+        KvsEngineSel::KvStore => {
+            let options = cfg.kvstore.options()?.logger(log.clone());
+            let store = KvStore::open_with(&path, options)?;
+            run_server(store, engine, backup_dir, log, cfg.addr)
+        }
+        KvsEngineSel::SledKvsEngine => run_server(
+            SledKvsEngine::open(&path)?,
+            engine,
+            backup_dir,
+            log,
+            cfg.addr,
+        ),
+    }
+}
+
+// create the backup dir if missing, which must not be in the dir of the db at `path`,
+// or the backups would end up among its files.
+fn backup_dir(path: &Path, dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let dir = dir.canonicalize()?;
+    if dir.starts_with(path.canonicalize()?) {
+        return Err(KvsError::InvalidOption(format!(
+            "backup dir `{}` is in the dir of the db",
+            dir.display()
+        )));
+    }
+    Ok(dir)
+}
+
+fn run_server(
+    engine: impl KvsEngine,
+    engine_sel: KvsEngineSel,
+    backup_dir: Option<PathBuf>,
+    log: &Logger,
+    addr: SocketAddr,
+) -> Result<()> {
+    let mut server = KvsServer::new(engine, log).engine_sel(engine_sel);
+    if let Some(dir) = backup_dir {
+        server = server.backup_dir(dir);
+    }
+    info!(log, "server listening on socket: {addr}");
+    server.run(addr)
 }
 
 fn current_engine(path: &Path) -> Result<Option<KvsEngineSel>> {
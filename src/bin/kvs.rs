@@ -1,7 +1,19 @@
+use kvs::cli::KvStoreFlags;
+use kvs::engine::{
+    jsonl::{self, ImportOptions},
+    sled::SledKvsEngine,
+};
+use kvs::server::{KvsEngineSel, ENGINE_MARKER};
 use kvs::KvsEngine;
 use kvs::Result;
 use kvs::{error::KvsError, KvStore};
-use std::{path::PathBuf, process::exit};
+use slog::{o, Drain, Logger};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::exit,
+};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -10,6 +22,11 @@ struct Config {
     cmd: Option<Cmd>,
     #[structopt(default_value = ".")]
     db_path: PathBuf,
+    /// the engine of the db, taken from the db dir if `kvs-server` has written it there
+    #[structopt(long)]
+    engine: Option<KvsEngineSel>,
+    #[structopt(flatten)]
+    kvstore: KvStoreFlags,
 }
 
 #[derive(StructOpt)]
@@ -25,37 +42,137 @@ enum Cmd {
     Remove {
         key: String,
     },
+    /// write every key/value pair as JSON Lines
+    Export {
+        /// the file to write, stdout if not given
+        file: Option<PathBuf>,
+    },
+    /// set the key/value pairs of a JSON Lines file written by `export`
+    Import {
+        /// the file to read, stdin if not given
+        file: Option<PathBuf>,
+        /// write the pairs in atomic batches of this many
+        #[structopt(long)]
+        batch_size: Option<usize>,
+        /// only report the keys which would be overwritten with another value
+        #[structopt(long)]
+        dry_run: bool,
+    },
 }
 
 fn main() {
-    let r = run_app();
+    // warnings of the engine, such as a torn record discarded on open, go to stderr
+    let decorator = slog_term::TermDecorator::new().stderr().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    let logger = Logger::root(drain, o!());
+    let r = run_app(&logger);
+    // `exit` runs no destructor, so flush the async drain first
+    drop(logger);
     if let Err(e) = r {
-        if let KvsError::KeyNotFound { key: _ } = e {
-            println!("Key not found");
+        match e {
+            KvsError::KeyNotFound { key: _ } => println!("Key not found"),
+            KvsError::CommandError(_) => {}
+            e => eprintln!("{e}"),
         }
         exit(1)
     }
 }
 
-fn run_app() -> Result<()> {
+fn run_app(log: &Logger) -> Result<()> {
     let cfg = Config::from_args();
-    if let Some(cmd) = cfg.cmd {
-        use Cmd::*;
-        let mut kvstore = KvStore::open(cfg.db_path).expect("open db file failed");
-        match cmd {
-            Set { key, value } => kvstore.set(key, value),
-            Get { key } => {
-                let value = kvstore.get(key)?;
-                match value {
-                    Some(s) => println!("{s}"),
-                    None => println!("Key not found"),
+    let Some(cmd) = cfg.cmd else {
+        eprintln!("run `kvs --help` to get help messages");
+        return Err(KvsError::CommandError("unknown command"));
+    };
+    match engine_sel(&cfg.db_path, cfg.engine)? {
+        KvsEngineSel::KvStore => {
+            let options = cfg.kvstore.options()?.logger(log.clone());
+            let kvstore = KvStore::open_with(cfg.db_path, options)?;
+            run_cmd(kvstore, cmd)
+        }
+        KvsEngineSel::SledKvsEngine => run_cmd(SledKvsEngine::open(cfg.db_path)?, cmd),
+    }
+}
+
+fn run_cmd(engine: impl KvsEngine, cmd: Cmd) -> Result<()> {
+    use Cmd::*;
+    match cmd {
+        Set { key, value } => engine.set_str(key, value),
+        Get { key } => {
+            match engine.get(key.as_bytes())? {
+                // the value may not be UTF-8, print it as it is
+                Some(value) => {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(&value)?;
+                    stdout.write_all(b"\n")?;
                 }
-                Ok(())
+                None => println!("Key not found"),
             }
-            Remove { key } => kvstore.remove(key),
+            Ok(())
         }
-    } else {
-        eprintln!("run `kvs --help` to get help messages");
-        Err(KvsError::CommandError("unknown command"))
+        Remove { key } => engine.remove_str(key),
+        Export { file } => {
+            let count = match file {
+                Some(file) => jsonl::export(&engine, BufWriter::new(File::create(file)?))?,
+                None => jsonl::export(&engine, io::stdout().lock())?,
+            };
+            eprintln!("exported {count} pairs");
+            Ok(())
+        }
+        Import {
+            file,
+            batch_size,
+            dry_run,
+        } => {
+            let mut options = ImportOptions::new().dry_run(dry_run);
+            if let Some(batch_size) = batch_size {
+                options = options.batch_size(batch_size);
+            }
+            let report = match file {
+                Some(file) => jsonl::import(&engine, BufReader::new(File::open(file)?), options)?,
+                None => jsonl::import(&engine, io::stdin().lock(), options)?,
+            };
+            for key in &report.conflicts {
+                println!("conflict: {}", String::from_utf8_lossy(key));
+            }
+            for key in &report.repeated {
+                println!("repeated: {}", String::from_utf8_lossy(key));
+            }
+            let verb = if dry_run { "would import" } else { "imported" };
+            println!(
+                "{verb} {} pairs: {} new, {} unchanged, {} conflicts, {} repeated",
+                report.pairs,
+                report.new,
+                report.unchanged,
+                report.conflicts.len(),
+                report.repeated.len()
+            );
+            Ok(())
+        }
+    }
+}
+
+// the engine written by `kvs-server` in the db dir wins, and the one given is written there
+// for a new db, so the db is opened with the same engine afterwards.
+fn engine_sel(db_path: &Path, e_cli: Option<KvsEngineSel>) -> Result<KvsEngineSel> {
+    let marker = db_path.join(ENGINE_MARKER);
+    match fs::read_to_string(&marker) {
+        Ok(buf) => {
+            let e_disk = buf.parse()?;
+            match e_cli {
+                Some(e_cli) if e_cli != e_disk => Err(KvsError::MisMatchEngine { e_disk, e_cli }),
+                _ => Ok(e_disk),
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match e_cli {
+            Some(e_cli) => {
+                fs::create_dir_all(db_path)?;
+                fs::write(marker, e_cli.to_string())?;
+                Ok(e_cli)
+            }
+            None => Ok(KvsEngineSel::default()),
+        },
+        Err(e) => Err(e.into()),
     }
 }
@@ -1,26 +1,147 @@
+use crate::engine::{
+    kvstore::{Compression, EncryptionKey, KvStoreOptions, SyncPolicy},
+    CompareAndSwapError, EngineStats, WriteBatch,
+};
 use serde::{Deserialize, Serialize};
+use std::{env, fs, path::PathBuf, time::Duration};
 use structopt::StructOpt;
 
 type Result<T> = std::result::Result<T, String>;
 
-#[derive(Debug, Deserialize, Serialize, StructOpt)]
+/// a request sent from the client to the server.
+///
+/// requests and responses are encoded with bincode,
+/// so keys and values go over the wire as they are, without any escaping.
+#[derive(Debug, Deserialize, Serialize)]
 pub enum Request {
+    /// the key expires after `ttl` if given
     Set {
-        key: String,
-        value: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
     },
     Get {
-        key: String,
+        key: Vec<u8>,
     },
-    #[structopt(alias = "rm")] // rm is the subcmd used by test
     Remove {
-        key: String,
+        key: Vec<u8>,
+    },
+    /// apply the writes atomically
+    Batch(WriteBatch),
+    /// set the key to `new` only if it holds `expected`, `None` for no value
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    /// begin a transaction on the connection,
+    /// which `Get`, `Set` and `Remove` go through until `Commit` or `Abort`
+    Begin,
+    Commit,
+    Abort,
+    /// report the keys and the data files of the engine
+    Stats,
+    /// write a checkpoint of the db into `dest`, a missing or empty dir on the server,
+    /// relative to the backup dir of the server and without `..`
+    Backup {
+        dest: PathBuf,
     },
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Response {
     Set(Result<()>),
-    Get(Result<Option<String>>),
+    Get(Result<Option<Vec<u8>>>),
     Remove(Result<()>),
+    Batch(Result<()>),
+    /// the inner error holds the current value if it is not the expected one
+    CompareAndSwap(Result<std::result::Result<(), CompareAndSwapError>>),
+    Begin(Result<()>),
+    /// `Ok(false)` if the transaction conflicted and nothing was written, so it should be retried
+    Commit(Result<bool>),
+    Abort(Result<()>),
+    Stats(Result<EngineStats>),
+    Backup(Result<()>),
+}
+
+/// flags to tune the kvs engine, the defaults of `KvStoreOptions` are used if not given.
+#[derive(Debug, StructOpt)]
+pub struct KvStoreFlags {
+    /// trigger compaction only when the invalid size is at least this many bytes
+    #[structopt(long)]
+    compaction_threshold: Option<usize>,
+    /// trigger compaction only when the invalid size is at least this ratio of all files
+    #[structopt(long, parse(try_from_str = parse_ratio))]
+    garbage_ratio: Option<f64>,
+    /// start writing to a new kvs file when the current one reaches this many bytes
+    #[structopt(long)]
+    max_segment_size: Option<u64>,
+    /// when writes are made durable: `always`, `never` or `interval:<ms>`
+    #[structopt(long)]
+    sync: Option<SyncPolicy>,
+    /// the codec values are compressed with: `none`, `lz4` or `snappy`
+    #[structopt(long)]
+    compression: Option<Compression>,
+    /// store values of at least this many bytes in blob files apart from the keys
+    #[structopt(long)]
+    blob_threshold: Option<usize>,
+    /// cache up to this many bytes of values in memory
+    #[structopt(long)]
+    value_cache: Option<usize>,
+    /// encrypt the data files with the key in this file, 32 bytes or 64 hex digits.
+    /// without it, the key is taken in hex from `KVS_ENCRYPTION_KEY` if set
+    #[structopt(long, parse(from_os_str))]
+    key_file: Option<PathBuf>,
+    /// a key the data files were encrypted with before, only to read them. may be given more than once
+    #[structopt(long, parse(from_os_str))]
+    old_key_file: Vec<PathBuf>,
+    /// open the store read-only
+    #[structopt(long)]
+    read_only: bool,
+}
+
+/// the environment variable holding the encryption key in hex, if `--key-file` is not given.
+pub const ENCRYPTION_KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+impl KvStoreFlags {
+    pub fn options(&self) -> crate::Result<KvStoreOptions> {
+        let mut options = KvStoreOptions::new().read_only(self.read_only);
+        if let Some(bytes) = self.compaction_threshold {
+            options = options.compaction_threshold(bytes);
+        }
+        if let Some(ratio) = self.garbage_ratio {
+            options = options.garbage_ratio(ratio);
+        }
+        if let Some(bytes) = self.max_segment_size {
+            options = options.max_segment_size(bytes);
+        }
+        if let Some(policy) = self.sync {
+            options = options.sync_policy(policy);
+        }
+        if let Some(compression) = self.compression {
+            options = options.compression(compression);
+        }
+        if let Some(bytes) = self.blob_threshold {
+            options = options.blob_threshold(bytes);
+        }
+        if let Some(bytes) = self.value_cache {
+            options = options.value_cache(bytes);
+        }
+        if let Some(path) = &self.key_file {
+            options = options.encryption_key(EncryptionKey::from_bytes(&fs::read(path)?)?);
+        } else if let Ok(hex) = env::var(ENCRYPTION_KEY_ENV) {
+            options = options.encryption_key(hex.parse()?);
+        }
+        for path in self.old_key_file.iter() {
+            options = options.decryption_key(EncryptionKey::from_bytes(&fs::read(path)?)?);
+        }
+        Ok(options)
+    }
+}
+
+fn parse_ratio(s: &str) -> Result<f64> {
+    match s.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("`{s}` is not a ratio in 0.0..=1.0")),
+    }
 }
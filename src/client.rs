@@ -2,8 +2,6 @@ use crate::{
     cli::{Request, Response},
     Result,
 };
-use serde::Deserialize;
-use serde_json::Deserializer;
 use std::{
     io::{BufReader, BufWriter, Write},
     net::{TcpStream, ToSocketAddrs},
@@ -22,10 +20,8 @@ impl KvsClient {
         Ok(Self { reader, writer })
     }
     pub fn send_request(&mut self, request: &Request) -> Result<Response> {
-        serde_json::to_writer(&mut self.writer, &request)?;
+        bincode::serialize_into(&mut self.writer, &request)?;
         self.writer.flush()?;
-        Ok(Response::deserialize(&mut Deserializer::from_reader(
-            &mut self.reader,
-        ))?)
+        Ok(bincode::deserialize_from(&mut self.reader)?)
     }
 }
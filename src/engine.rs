@@ -1,11 +1,188 @@
 use crate::error::KvsError;
+use serde::{Deserialize, Serialize};
+use std::{
+    ops::RangeBounds,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+mod batch;
+pub mod jsonl;
 pub mod kvstore;
 pub mod sled;
+mod transaction;
+pub use batch::{BatchOp, WriteBatch};
+pub use transaction::Transaction;
 pub type Result<T> = std::result::Result<T, KvsError>;
+/// key/value pairs in ascending order of the keys.
+pub type KvPairs = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+/// keys in ascending order.
+pub type Keys = Box<dyn Iterator<Item = Result<Vec<u8>>> + Send>;
 
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
+/// returned by `KvsEngine::compare_and_swap` when the key does not hold the expected value.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompareAndSwapError {
+    /// the value the key holds, `None` if it does not exist
+    pub current: Option<Vec<u8>>,
+}
+
+/// what an engine holds on disk, returned by `KvsEngine::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// keys which exist and have not expired
+    pub keys: u64,
+    /// size of the records of the live keys, in bytes
+    pub live_bytes: u64,
+    /// size of the data files not taken by the live records, in bytes,
+    /// mostly records overwritten, removed or expired which compaction has yet to drop
+    pub dead_bytes: u64,
+    /// size of every data file, in bytes, the oldest first
+    pub segment_sizes: Vec<u64>,
+    /// when the last compaction finished, in milliseconds since the UNIX epoch,
+    /// `None` if none has run since the engine was opened
+    pub last_compaction: Option<u64>,
+    /// compactions run since the engine was opened
+    pub compactions: u64,
+}
+
+impl EngineStats {
+    /// the number of data files.
+    pub fn segment_count(&self) -> usize {
+        self.segment_sizes.len()
+    }
+}
+
+impl std::fmt::Display for EngineStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "keys: {}", self.keys)?;
+        writeln!(f, "live bytes: {}", self.live_bytes)?;
+        writeln!(f, "dead bytes: {}", self.dead_bytes)?;
+        let sizes = self
+            .segment_sizes
+            .iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>();
+        writeln!(
+            f,
+            "segments: {} [{}]",
+            self.segment_count(),
+            sizes.join(", ")
+        )?;
+        match self.last_compaction {
+            Some(ms) => writeln!(f, "last compaction: {ms}")?,
+            None => writeln!(f, "last compaction: never")?,
+        }
+        writeln!(f, "compactions: {}", self.compactions)
+    }
+}
+
+/// a storage engine that can be shared between threads.
+/// cloning an engine is cheap and every clone refers to the same db.
+///
+/// keys and values are arbitrary bytes, the `*_str` helpers are there for UTF-8 strings.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// the version of the value of a key, which changes whenever the key is written.
+    type Version: Clone + PartialEq + Send + 'static;
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// set a key which expires after `ttl`, as if it had been removed.
+    /// setting the key again without a TTL makes it permanent.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn remove(&self, key: &[u8]) -> Result<()>;
+    /// apply every write of `batch` or none of them, even across a crash.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// set the key to `new` only if it holds `expected`, atomically.
+    /// `None` stands for a key that does not exist, so `new: None` removes the key.
+    /// return the current value in the inner error if it is not the expected one.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>>;
+    /// get the value of a key together with its version, for transactions.
+    fn get_versioned(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Self::Version)>;
+    /// apply `writes` atomically only if every key in `reads` still has the version read,
+    /// return `Ok(false)` without writing anything otherwise.
+    fn commit(&self, reads: &[(Vec<u8>, Self::Version)], writes: WriteBatch) -> Result<bool>;
+    /// iterate over the keys in `range` and their values.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvPairs>;
+    /// iterate over the keys starting with `prefix` and their values.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvPairs>;
+    /// iterate over every key without reading the values.
+    fn keys(&self) -> Result<Keys>;
+    /// report the keys, the live and dead bytes and the data files of the engine.
+    fn stats(&self) -> Result<EngineStats>;
+    /// write a copy of the db into `dest`, which should be missing or empty,
+    /// while reads and writes go on. the copy can be opened as a db of its own.
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()>;
+
+    fn set_str(&self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        self.set(key.into().into_bytes(), value.into().into_bytes())
+    }
+    /// return an error if the value is not valid UTF-8.
+    fn get_str(&self, key: impl AsRef<str>) -> Result<Option<String>> {
+        match self.get(key.as_ref().as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+    fn remove_str(&self, key: impl AsRef<str>) -> Result<()> {
+        self.remove(key.as_ref().as_bytes())
+    }
+
+    /// iterate over every key and its value.
+    /// an error while reading a value is returned by the iterator, which goes on after it.
+    fn iter(&self) -> Result<KvPairs> {
+        self.scan(..)
+    }
+
+    /// run `f` in an optimistic transaction and commit it.
+    /// `f` is run again from scratch whenever the commit conflicts with another write,
+    /// and the transaction is aborted if `f` returns an error.
+    ///
+    /// ```no_run
+    /// # use kvs::{KvStore, KvsEngine};
+    /// # fn main() -> kvs::Result<()> {
+    /// let store = KvStore::open("db")?;
+    /// store.transaction(|txn| {
+    ///     let from = txn.get(b"from")?.unwrap_or_default();
+    ///     let to = txn.get(b"to")?.unwrap_or_default();
+    ///     txn.set(b"from".to_vec(), to);
+    ///     txn.set(b"to".to_vec(), from);
+    ///     Ok(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn transaction<T>(
+        &self,
+        mut f: impl FnMut(&mut Transaction<'_, Self>) -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            let mut txn = Transaction::new(self);
+            let ret = f(&mut txn)?;
+            if txn.commit()? {
+                return Ok(ret);
+            }
+        }
+    }
+
+    /// set the key only if it does not exist, return whether it was set.
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        Ok(self.compare_and_swap(key, None, Some(value))?.is_ok())
+    }
+}
+
+/// milliseconds since the UNIX epoch, which is how expiry times are stored.
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// the time a key set now with `ttl` expires at.
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_ms().saturating_add(ttl.as_millis() as u64)
 }
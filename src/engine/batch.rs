@@ -0,0 +1,87 @@
+//! write batches, applied atomically by `KvsEngine::write_batch`.
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// a write in a `WriteBatch`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// set the value of a key, which expires after `ttl` if given
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    /// remove a key, which does nothing if the key does not exist
+    Remove { key: Vec<u8> },
+}
+
+/// a list of writes applied all or nothing, in order.
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, WriteBatch};
+/// # fn main() -> kvs::Result<()> {
+/// let store = KvStore::open("db")?;
+/// let mut batch = WriteBatch::new();
+/// batch.set(b"from".to_vec(), b"90".to_vec());
+/// batch.set(b"to".to_vec(), b"110".to_vec());
+/// batch.remove(b"pending".to_vec());
+/// store.write_batch(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            ttl: None,
+        });
+        self
+    }
+
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+        });
+        self
+    }
+
+    /// unlike `KvsEngine::remove`, removing a key that does not exist is not an error.
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    pub fn push(&mut self, op: BatchOp) -> &mut Self {
+        self.ops.push(op);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
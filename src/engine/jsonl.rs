@@ -0,0 +1,157 @@
+//! export and import of every key/value pair as JSON Lines, one `{"key":..,"value":..}` per line.
+//!
+//! a key or value which is valid UTF-8 is written as a JSON string,
+//! any other as an array of its bytes, so every pair reads back as it was.
+//! TTLs are not exported: a key which has yet to expire is exported as a permanent one.
+use super::{KvsEngine, Result, WriteBatch};
+use crate::error::KvsError;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    io::{BufRead, Write},
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Bytes {
+    Str(String),
+    Raw(Vec<u8>),
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        String::from_utf8(bytes).map_or_else(|e| Self::Raw(e.into_bytes()), Self::Str)
+    }
+}
+
+impl From<Bytes> for Vec<u8> {
+    fn from(bytes: Bytes) -> Self {
+        match bytes {
+            Bytes::Str(s) => s.into_bytes(),
+            Bytes::Raw(raw) => raw,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Line {
+    key: Bytes,
+    value: Bytes,
+}
+
+/// write every pair of `engine` into `writer`, in ascending order of the keys,
+/// and return how many are written.
+/// stop at the first pair which fails to be read, rather than leave it out of the export.
+pub fn export(engine: &impl KvsEngine, mut writer: impl Write) -> Result<u64> {
+    let mut count = 0;
+    for pair in engine.iter()? {
+        let (key, value) = pair?;
+        let line = Line {
+            key: key.into(),
+            value: value.into(),
+        };
+        serde_json::to_writer(&mut writer, &line)?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// how `import` writes the pairs it reads.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImportOptions {
+    batch_size: Option<usize>,
+    dry_run: bool,
+}
+
+impl ImportOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// write the pairs in atomic batches of this many, rather than one by one.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = Some(batch_size).filter(|&n| n > 0);
+        self
+    }
+
+    /// only report what would be written, without writing anything.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+/// what `import` has written, or would have in a dry run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// pairs read from the input
+    pub pairs: u64,
+    /// keys which did not exist
+    pub new: u64,
+    /// keys which already held the value, which are not written again
+    pub unchanged: u64,
+    /// keys which held another value, which is overwritten, in the order they are read
+    pub conflicts: Vec<Vec<u8>>,
+    /// keys read again after their first pair, once for every pair after it, in the order they are read
+    pub repeated: Vec<Vec<u8>>,
+}
+
+/// read the pairs written by `export` from `reader` and set them in `engine`.
+/// a key is checked against the value it holds before the import, the first time it is read:
+/// the pairs after it are only reported as `repeated`, and the last one is written,
+/// whether the pairs are written in batches or not, or not at all in a dry run.
+/// blank lines are skipped, and a line which is not a pair fails the import,
+/// after the pairs before it are written unless they are still in a batch.
+pub fn import(
+    engine: &impl KvsEngine,
+    reader: impl BufRead,
+    options: ImportOptions,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut batch = WriteBatch::new();
+    // every key read so far, as the ones written are no longer checked against their value before
+    let mut seen = HashSet::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Line { key, value } =
+            serde_json::from_str(&line).map_err(|source| KvsError::Import {
+                line: n as u64 + 1,
+                source,
+            })?;
+        let (key, value) = (Vec::from(key), Vec::from(value));
+        report.pairs += 1;
+        if !seen.insert(key.clone()) {
+            report.repeated.push(key.clone());
+        } else {
+            match engine.get(&key)? {
+                None => report.new += 1,
+                Some(current) if current == value => {
+                    report.unchanged += 1;
+                    continue;
+                }
+                Some(_) => report.conflicts.push(key.clone()),
+            }
+        }
+        if options.dry_run {
+            continue;
+        }
+        match options.batch_size {
+            Some(batch_size) => {
+                batch.set(key, value);
+                if batch.len() >= batch_size {
+                    engine.write_batch(std::mem::take(&mut batch))?;
+                }
+            }
+            None => engine.set(key, value)?,
+        }
+    }
+    if !batch.is_empty() {
+        engine.write_batch(batch)?;
+    }
+    Ok(report)
+}
@@ -1,36 +1,92 @@
 #![deny(missing_docs)]
 //! this is a crate doc
-use super::Result;
+use self::{
+    blob::{BlobCollector, BlobFiles, BlobGcWorker, BlobLog, BlobPointer},
+    cache::ValueCache,
+    compaction::{CompactionHistory, CompactionJob, CompactionWorker, Compactor},
+    compression::Compressor,
+    encryption::Keyring,
+    expiry::{now_ms, Deadlines, Evicted, Sweeper},
+    hint::Hint,
+    index::Index,
+    manifest::Manifest,
+    record::{RecordError, RecordType},
+    segment::Segment,
+    snapshot::Pins,
+    sync::{IntervalSyncer, Syncer},
+};
+use super::{BatchOp, CompareAndSwapError, EngineStats, Keys, KvPairs, Result, WriteBatch};
 use crate::{
-    error::{KvsError, KvsResult},
+    error::{Corruption, KvsError, KvsResult},
     KvsEngine,
 };
 use serde::{Deserialize, Serialize};
+use slog::warn;
 use std::{
+    borrow::Cow,
     collections::HashMap,
     ffi::OsStr,
     fs::{self, remove_file, File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
     mem,
+    ops::{Bound, Deref, RangeBounds},
     path::{Path, PathBuf},
+    slice,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
+    },
+    time::Duration,
 };
 
-/// when the invalid size is larger than `COMPACTION_THRESHOLD`(in bytes), a compaction process will be triggered.
-const COMPACTION_THRESHOLD: usize = 4 * 1024 * 1024;
+mod blob;
+mod cache;
+mod checkpoint;
+mod compaction;
+mod compression;
+mod encryption;
+mod expiry;
+mod hint;
+mod index;
+mod legacy;
+mod manifest;
+mod options;
+mod record;
+mod segment;
+mod snapshot;
+mod sync;
+
+pub use cache::CacheStats;
+pub use compression::{Compression, CompressionStats};
+pub use encryption::EncryptionKey;
+pub use options::{KvStoreOptions, SyncPolicy};
+pub use snapshot::Snapshot;
 
 type FileId = u32;
-type Key = String;
-type Value = String;
-type Index = HashMap<Key, LogMeta>;
+type Key = Vec<u8>;
+type Value = Vec<u8>;
+// the position and length of every record in a batch record
+type Positions = Vec<(usize, usize)>;
 
 /// KvStore
 /// the main struct of KVS
+///
+/// `KvStore` is cheap to clone and every clone shares the same db.
+/// reads run concurrently, on any clone or on a shared one, while writes are serialized.
+#[derive(Clone)]
 pub struct KvStore {
-    index: Index,
-    path: PathBuf,
-    files: HashMap<FileId, File>,
-    write_id: FileId,
-    uncompact_size: usize,
+    index: Arc<RwLock<Index>>,
+    reader: KvStoreReader,
+    // `None` if the store is opened read-only
+    writer: Option<Arc<SharedWriter>>,
+    // writes wait for it only with `SyncPolicy::Always`
+    syncer: Option<Arc<Syncer>>,
+    // sequence number of the last write in the index
+    seq: Arc<AtomicU64>,
+    pins: Arc<Pins>,
+    compressor: Arc<Compressor>,
+    manifest: Arc<Mutex<Manifest>>,
+    compactions: Arc<CompactionHistory>,
 }
 
 impl KvStore {
@@ -39,37 +95,125 @@ impl KvStore {
     /// this function will use the kvs files to build a KvStore db and return it if succeed.
     /// If any error met, this function will return it.
     pub fn open(path: impl AsRef<Path>) -> KvsResult<Self> {
-        fn load(index: &mut Index, file_id: FileId, db_file: &mut File) -> KvsResult<usize> {
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// same as `open`, but with the given options.
+    ///
+    /// a write torn by a crash can only leave an incomplete record at the tail of the newest file,
+    /// so such a record is truncated away with a warning to the logger of `options`.
+    /// any other corrupted record is still a hard error.
+    pub fn open_with(path: impl AsRef<Path>, options: KvStoreOptions) -> KvsResult<Self> {
+        fn load(
+            index: &mut Index,
+            path: &Path,
+            file_id: FileId,
+            is_newest: bool,
+            keyring: &Keyring,
+            options: &KvStoreOptions,
+        ) -> KvsResult<usize> {
             let mut uncompact_size = 0;
-            let mut offset = db_file.seek(SeekFrom::Start(0))?;
-            let mut t = serde_json::Deserializer::from_reader(db_file).into_iter::<Log>();
-            while let Some(cmd) = t.next() {
-                let new_offset = t.byte_offset() as u64;
-                match cmd? {
-                    Log { key, value: None } => {
-                        uncompact_size += index.remove(&key).map(|l| l.len).unwrap()
+            let mut db_file = open_ro(path, file_id)?;
+            let mut offset = 0;
+            let mut reader = BufReader::new(&mut db_file);
+            let now = now_ms();
+            loop {
+                let corrupted = |reason| corrupted(file_id, offset, reason);
+                let buf = match record::read_record(&mut reader) {
+                    Ok(Some(buf)) => buf,
+                    Ok(None) => break,
+                    Err(RecordError::Corrupted(Corruption::Truncated))
+                        if is_newest && is_torn_tail(path, file_id, offset)? =>
+                    {
+                        let file_len = db_file.metadata()?.len();
+                        warn!(options.logger, "discarding torn record at the tail of the newest kvs file";
+                            "file_id" => file_id,
+                            "offset" => offset,
+                            "discarded_bytes" => file_len - offset);
+                        // a read-only store just stops reading there
+                        if !options.read_only {
+                            let file = OpenOptions::new()
+                                .write(true)
+                                .open(get_path(path, file_id))?;
+                            file.set_len(offset)?;
+                            file.sync_all()?;
+                        }
+                        break;
                     }
-                    Log { key, value: _ } => {
-                        uncompact_size += index
-                            .insert(
-                                key,
-                                LogMeta {
-                                    file_id,
-                                    offset,
-                                    len: (new_offset - offset) as usize,
-                                },
-                            )
-                            .map(|l| l.len)
-                            .unwrap_or(0);
+                    Err(RecordError::Io(e)) => return Err(e.into()),
+                    Err(RecordError::Corrupted(reason)) => return Err(corrupted(reason)),
+                };
+                let len = buf.len();
+                let (t, payload) = record::decode(&buf, keyring).map_err(corrupted)?;
+                let logs = match t {
+                    RecordType::Batch => {
+                        let mut logs = Vec::new();
+                        for (pos, inner) in record::split_batch(&payload).map_err(corrupted)? {
+                            let (t, payload) = record::decode(inner, keyring).map_err(corrupted)?;
+                            let log_offset = offset + (record::HEADER_LEN + pos) as u64;
+                            logs.push((log_offset, inner.len(), t, payload));
+                        }
+                        logs
                     }
+                    t => vec![(offset, len, t, Cow::Borrowed(&*payload))],
+                };
+                for (offset, len, t, payload) in logs {
+                    let entry = parse_entry(t, &payload).map_err(corrupted)?;
+                    uncompact_size += replay(index, entry, file_id, offset, len, now);
                 }
-                offset = new_offset;
+                offset += len as u64;
             }
             Ok(uncompact_size)
         }
 
+        // a tear can only follow a valid record, unless the file holds nothing but a record
+        // of a known type which is cut short, so a file which is not a kvs file at all is kept.
+        fn is_torn_tail(path: &Path, file_id: FileId, offset: u64) -> KvsResult<bool> {
+            if offset > 0 {
+                return Ok(true);
+            }
+            let file = open_ro(path, file_id)?;
+            let available = file.metadata()?.len();
+            let mut header = Vec::with_capacity(record::HEADER_LEN);
+            file.take(record::HEADER_LEN as u64)
+                .read_to_end(&mut header)?;
+            Ok(record::is_torn(&header, available))
+        }
+
+        // apply an entry read from disk to the index and return how much garbage it makes.
+        fn replay(
+            index: &mut Index,
+            entry: Entry,
+            file_id: FileId,
+            offset: u64,
+            len: usize,
+            now: u64,
+        ) -> usize {
+            let meta = LogMeta {
+                file_id,
+                offset,
+                len,
+                expires_at: entry.expires_at(),
+                version: 0,
+                blob: entry.blob(),
+            };
+            let removes = entry.is_tombstone();
+            let (key, _) = entry.into_key_value();
+            if removes {
+                // the key may have expired before it was removed
+                index.remove(&key).map_or(0, |l| l.len)
+            } else if meta.is_expired(now) {
+                // an expired log removes the key, like a tombstone
+                len + index.remove(&key).map_or(0, |l| l.len)
+            } else {
+                index.insert(key, meta).map_or(0, |l| l.len)
+            }
+        }
+
         let path = path.as_ref();
-        fs::create_dir_all(path)?;
+        if !options.read_only {
+            fs::create_dir_all(path)?;
+        }
         let log_list = fs::read_dir(path)?;
         let log_list = {
             let mut log_list = log_list
@@ -81,154 +225,969 @@ impl KvStore {
             log_list.sort_unstable();
             log_list
         };
-        let mut files = HashMap::new();
+        // without a MANIFEST(created by an older version), every kvs file is live
+        let manifest = match Manifest::load(path)? {
+            Some(manifest) => manifest,
+            None => {
+                for &i in &log_list {
+                    legacy::upgrade(path, i, options.read_only)?;
+                }
+                Manifest::new(path, log_list.iter().copied())
+            }
+        };
+        let log_list = {
+            let live = manifest.files().collect::<Vec<_>>();
+            for leftover in log_list.into_iter().filter(|i| !live.contains(i)) {
+                warn!(options.logger, "ignoring kvs file not in the MANIFEST"; "file_id" => leftover);
+                if !options.read_only {
+                    remove_kvs_file(path, leftover)?;
+                }
+            }
+            live
+        };
+        let keyring = Arc::new(Keyring::new(
+            options.encryption_key.as_ref(),
+            &options.decryption_keys,
+        ));
         let mut uncompact_size = 0;
         let mut index = Index::new();
+        let now = now_ms();
         for &i in log_list.iter() {
-            let mut file = open_ro(path, i)?;
-            uncompact_size += load(&mut index, i, &mut file)?;
-            files.insert(i, file);
+            if let Some(hints) = hint::read_hints(path, i, &keyring, &options.logger)? {
+                for hint in hints {
+                    let meta = hint.meta();
+                    uncompact_size += if meta.is_expired(now) {
+                        meta.len + index.remove(&hint.key).map(|l| l.len).unwrap_or(0)
+                    } else {
+                        index.insert(hint.key, meta).map(|l| l.len).unwrap_or(0)
+                    };
+                }
+                continue;
+            }
+            let is_newest = Some(&i) == log_list.last();
+            uncompact_size += load(&mut index, path, i, is_newest, &keyring, &options)?;
+        }
+        if !options.read_only {
+            uncompact_size += blob::drop_lost(path, &mut index, &options.logger)?;
         }
+        let live_size = index.values().map(|meta| meta.len).sum();
+
+        let path = Arc::new(path.to_path_buf());
+        let index = Arc::new(RwLock::new(index));
         let write_id = log_list.last().unwrap_or(&0) + 1;
-        let write_path = get_path(path, write_id);
+        let safe_point = Arc::new(AtomicU32::new(
+            log_list.first().copied().unwrap_or(write_id),
+        ));
+        let active_id = Arc::new(AtomicU32::new(write_id));
+        let cache = options
+            .value_cache
+            .map(|capacity| Arc::new(ValueCache::new(capacity)));
+        let reader = KvStoreReader::new(
+            path.clone(),
+            safe_point,
+            active_id.clone(),
+            keyring.clone(),
+            cache.clone(),
+        );
+        let seq = Arc::new(AtomicU64::new(0));
+        let pins = Arc::new(Pins::new());
+        let compressor = Arc::new(Compressor::new(options.compression));
+        // a read-only store never updates it
+        let manifest = Arc::new(Mutex::new(manifest));
+        if options.read_only {
+            return Ok(Self {
+                index,
+                reader,
+                writer: None,
+                syncer: None,
+                seq,
+                pins,
+                compressor,
+                manifest,
+                compactions: Arc::default(),
+            });
+        }
+
+        let blobs = BlobFiles::open(path.clone(), &index.read().unwrap(), &keyring)?;
+        let write_path = get_path(&path, write_id);
         let write_file = OpenOptions::new()
             .create_new(true)
             .append(true)
             .read(true)
             .open(write_path)?;
-        // let writer = LogWriter::new(new_file_id, new_file.try_clone()?);
-        files.insert(write_id, write_file);
+        manifest.lock().unwrap().update(|files| {
+            files.insert(write_id);
+        })?;
+        let syncer = Arc::new(Syncer::new(&write_file)?);
+        let interval_syncer = match options.sync_policy {
+            SyncPolicy::Interval(ms) => Some(IntervalSyncer::spawn(
+                syncer.clone(),
+                Duration::from_millis(ms),
+                options.logger.clone(),
+            )),
+            _ => None,
+        };
+        let always_sync = (options.sync_policy == SyncPolicy::Always).then(|| syncer.clone());
+        let evicted = Arc::new(Evicted::default());
+        let deadlines = Arc::new(Deadlines::new(&index.read().unwrap()));
+        let sweeper = Sweeper::spawn(
+            index.clone(),
+            deadlines.clone(),
+            evicted.clone(),
+            reader.cache.clone(),
+            options.sweep_interval,
+            options.logger.clone(),
+        );
+        let compactor = Compactor::new(
+            index.clone(),
+            reader.clone(),
+            path.clone(),
+            manifest.clone(),
+            evicted.clone(),
+            pins.clone(),
+            compressor.clone(),
+        );
+        let compactions = compactor.history.clone();
+        let collector = BlobCollector {
+            path: path.clone(),
+            index: index.clone(),
+            compressor: compressor.clone(),
+            keyring: keyring.clone(),
+        };
+        let writer = Arc::new_cyclic(|writer| {
+            let blob_gc = BlobGcWorker::spawn(collector, writer.clone(), options.logger.clone());
+            Mutex::new(KvStoreWriter {
+                index: index.clone(),
+                path,
+                manifest: manifest.clone(),
+                file_ids: log_list,
+                writer: write_file,
+                write_id,
+                active_id,
+                uncompact_size,
+                live_size,
+                compactor: compactor.clone(),
+                worker: CompactionWorker::spawn(compactor, options.logger.clone()),
+                syncer,
+                interval_syncer,
+                evicted,
+                deadlines,
+                sweeper,
+                seq: seq.clone(),
+                compressor: compressor.clone(),
+                keyring,
+                blobs,
+                blob_gc,
+                pins: pins.clone(),
+                cache,
+                options,
+            })
+        });
+        // blob files with records of an old key are rewritten from now on, see `blob`
+        writer.lock().unwrap().blob_gc_trigger();
         Ok(Self {
             index,
-            path: path.to_path_buf(),
-            files,
-            write_id,
-            uncompact_size,
+            reader,
+            writer: Some(Arc::new(SharedWriter(writer))),
+            syncer: always_sync,
+            seq,
+            pins,
+            compressor,
+            manifest,
+            compactions,
         })
     }
 
-    fn compaction_trigger(&mut self) -> KvsResult<()> {
-        if self.uncompact_size >= COMPACTION_THRESHOLD {
-            self.compaction_inner().map_err(|e| {
-                if let KvsError::Inner(s) = e {
-                    KvsError::CompactionError(s)
-                } else {
-                    panic!("unexpect error")
-                }
-            })
-        } else {
-            Ok(())
-        }
+    /// take a read-only snapshot of the store as it is now.
+    /// it sees no later write, and keeps the files it reads from until it is dropped.
+    ///
+    /// the snapshot shares the index with the store, see the `index` module,
+    /// and a write copies the chunk of the index it changes while a snapshot still shares it.
+    pub fn snapshot(&self) -> Snapshot {
+        let index = self.index.read().unwrap();
+        Snapshot::new(
+            index.clone(),
+            self.seq.load(Ordering::SeqCst),
+            &self.reader,
+            &self.pins,
+        )
     }
 
-    // note: index and writer are replaced by the new ones while readers are just cleared.
-    fn compaction_inner(&mut self) -> KvsResult<()> {
-        let new_write_id = self.write_id.wrapping_add(1);
-        let mut new_write_file = open_rw(&self.path, new_write_id)?;
-        let old_index = mem::take(&mut self.index);
-        for (key, meta) in old_index.into_iter() {
-            let log = read_log(self.files.get_mut(&meta.file_id).unwrap(), &meta)?;
-            debug_assert!(log.value.is_some());
-            let (offset, len) = write_log(&mut new_write_file, &log)?;
-            let new_meta = LogMeta {
-                file_id: new_write_id,
-                offset,
-                len,
-            };
-            self.index.insert(key, new_meta);
+    /// how well the logs written since the store was opened have been compressed,
+    /// see `KvStoreOptions::compression`.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.compressor.stats()
+    }
+
+    /// the hits and misses of the value cache since the store was opened,
+    /// `None` if there is no cache, see `KvStoreOptions::value_cache`.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.reader.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    fn writer(&self) -> KvsResult<MutexGuard<'_, KvStoreWriter>> {
+        match &self.writer {
+            Some(writer) => Ok(writer.lock().unwrap()),
+            None => Err(KvsError::ReadOnly),
         }
-        let new_files = {
-            let mut new_files = HashMap::new();
-            new_files.insert(new_write_id, new_write_file);
-            new_files
-        };
-        let old_files = mem::replace(&mut self.files, new_files);
-        for &to_rm_id in old_files.keys() {
-            remove_file(get_path(&self.path, to_rm_id))?;
+    }
+
+    // read the values of `keys` lazily.
+    fn pairs(&self, keys: Vec<Key>) -> KvPairs {
+        let store = self.clone();
+        Box::new(keys.into_iter().filter_map(move |key| {
+            store
+                .get(&key)
+                .transpose()
+                .map(|value| value.map(|value| (key, value)))
+        }))
+    }
+
+    // wait until the write `seq` is durable if the sync policy says so.
+    // this is done out of the writer lock, so concurrent writes share a sync.
+    fn wait_for_sync(&self, seq: u64) -> KvsResult<()> {
+        match &self.syncer {
+            Some(syncer) => syncer.wait_for(seq),
+            None => Ok(()),
         }
-        self.write_id = new_write_id;
-        self.uncompact_size = 0;
-        Ok(())
     }
 }
 impl KvsEngine for KvStore {
+    /// The sequence number of the write which set the key, `None` if the key does not exist.
+    type Version = Option<u64>;
+
     /// Set the value of a key.
     /// Return `Ok(())` if succeed.
     /// Return an error if the value is not set successfully.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let log = Log {
-            key,
-            value: Some(value),
-        };
-        let (offset, len) = write_log(self.files.get_mut(&self.write_id).unwrap(), &log)?;
-        self.uncompact_size += self
-            .index
-            .insert(
-                log.key,
-                LogMeta {
-                    file_id: self.write_id,
-                    offset,
-                    len,
-                },
-            )
-            .map(|l| l.len)
-            .unwrap_or(0);
-        self.compaction_trigger()?;
-        Ok(())
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let seq = self.writer()?.set(key, value, None)?;
+        self.wait_for_sync(seq)
+    }
+
+    /// Set the value of a key, which expires after `ttl`.
+    /// An expired key is not found any more, and its log is dropped by compaction.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let seq = self
+            .writer()?
+            .set(key, value, Some(expiry::expires_at(ttl)))?;
+        self.wait_for_sync(seq)
     }
 
     /// Get the value of a key.
     /// Return `Ok(Some(value))` if something is found.
     /// If the key does not exist, return `Ok(None)`.
     /// Return an error if the value is not read successfully.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.index.get(&key) {
-            Some(meta) => {
-                let log = read_log(self.files.get_mut(&meta.file_id).unwrap(), meta)?;
-                Ok(log.value)
-            }
-            None => Ok(None),
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // hold the read lock while reading so compaction cannot remove the file under us
+        let index = self.index.read().unwrap();
+        match index.get(key) {
+            Some(meta) if !meta.is_expired(now_ms()) => self.reader.read_value(key, meta),
+            _ => Ok(None),
         }
     }
 
     /// Remove a given key.
     /// Return an error if the key does not exist or is not removed successfully.
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let log = Log { key, value: None };
-            let (_offset, len) = write_log(self.files.get_mut(&self.write_id).unwrap(), &log)?;
-            self.uncompact_size += self.index.remove(&log.key).unwrap().len + len;
-            self.compaction_trigger()?;
-            Ok(())
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        let seq = self.writer()?.remove(key)?;
+        self.wait_for_sync(seq)
+    }
+
+    /// Apply a batch of writes atomically.
+    /// The writes are persisted in a single record, which `open` either replays fully or discards,
+    /// and readers see either none or all of them.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let seq = self.writer()?.write_batch(batch)?;
+        self.wait_for_sync(seq)
+    }
+
+    /// Set the key to `new` only if it holds `expected`.
+    /// The writer lock is held from the read to the write, so no other write gets in between.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        let mut writer = self.writer()?;
+        let current = self.get(&key)?;
+        if current != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+        let seq = match (new, current) {
+            (Some(value), _) => writer.set(key, value, None)?,
+            (None, Some(_)) => match writer.remove(&key) {
+                Ok(seq) => seq,
+                // it has just expired, which removes it as well
+                Err(KvsError::KeyNotFound { .. }) => return Ok(Ok(())),
+                Err(e) => return Err(e),
+            },
+            (None, None) => return Ok(Ok(())),
+        };
+        drop(writer);
+        self.wait_for_sync(seq)?;
+        Ok(Ok(()))
+    }
+
+    /// Get the value of a key and the sequence number of its write.
+    fn get_versioned(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Self::Version)> {
+        let index = self.index.read().unwrap();
+        match index.get(key) {
+            Some(meta) if !meta.is_expired(now_ms()) => {
+                Ok((self.reader.read_value(key, meta)?, Some(meta.version)))
+            }
+            _ => Ok((None, None)),
+        }
+    }
+
+    /// Apply `writes` as a batch if no key in `reads` has been written since.
+    /// The writer lock is held from the validation to the write, so no other write gets in between.
+    fn commit(&self, reads: &[(Vec<u8>, Self::Version)], writes: WriteBatch) -> Result<bool> {
+        let mut writer = self.writer()?;
+        {
+            let index = self.index.read().unwrap();
+            let now = now_ms();
+            let conflict = reads.iter().any(|(key, version)| {
+                let current = match index.get(key) {
+                    Some(meta) if !meta.is_expired(now) => Some(meta.version),
+                    _ => None,
+                };
+                current != *version
+            });
+            if conflict {
+                return Ok(false);
+            }
+        }
+        let seq = writer.write_batch(writes)?;
+        drop(writer);
+        self.wait_for_sync(seq)?;
+        Ok(true)
+    }
+
+    /// Iterate over the keys in `range` and their values.
+    /// The keys are taken when this is called, the values are read while iterating,
+    /// so a key removed in between is skipped.
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvPairs> {
+        let keys = self
+            .index
+            .read()
+            .unwrap()
+            .range(
+                range.start_bound().map(Vec::as_slice),
+                range.end_bound().map(Vec::as_slice),
+            )
+            .map(|(key, _)| key.clone())
+            .collect();
+        Ok(self.pairs(keys))
+    }
+
+    /// Iterate over the keys starting with `prefix` and their values.
+    /// The same as `scan`, the keys are taken when this is called.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvPairs> {
+        let keys = self
+            .index
+            .read()
+            .unwrap()
+            .range(Bound::Included(prefix), Bound::Unbounded)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        Ok(self.pairs(keys))
+    }
+
+    /// Iterate over the keys which have not expired, taken when this is called.
+    /// No value is read.
+    fn keys(&self) -> Result<Keys> {
+        let now = now_ms();
+        let keys = self
+            .index
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, meta)| !meta.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        Ok(Box::new(keys.into_iter().map(Ok)))
+    }
+
+    /// Write a checkpoint of the store as of now into `dest`, see the `checkpoint` module.
+    /// Only the files being written are copied, the others are hard-linked.
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        checkpoint::checkpoint(self, dest.as_ref())
+    }
+
+    /// Report the keys and the kvs files of the store.
+    /// The live bytes are the records of the live keys in the kvs files,
+    /// which do not include the values in blob files.
+    fn stats(&self) -> Result<EngineStats> {
+        let files = self.manifest.lock().unwrap().files().collect::<Vec<_>>();
+        let (keys, live_bytes) = {
+            let index = self.index.read().unwrap();
+            let now = now_ms();
+            index
+                .values()
+                .filter(|meta| !meta.is_expired(now))
+                .fold((0, 0), |(keys, bytes), meta| {
+                    (keys + 1, bytes + meta.len as u64)
+                })
+        };
+        let mut segment_sizes = Vec::with_capacity(files.len());
+        for id in files {
+            match fs::metadata(get_path(&self.reader.path, id)) {
+                Ok(metadata) => segment_sizes.push(metadata.len()),
+                // compacted away since the files were listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(EngineStats {
+            keys,
+            live_bytes,
+            dead_bytes: segment_sizes.iter().sum::<u64>().saturating_sub(live_bytes),
+            segment_sizes,
+            last_compaction: self.compactions.last_at(),
+            compactions: self.compactions.runs(),
+        })
+    }
+}
+
+/// a set of read handles to the kvs files, shared by every clone of `KvStore`.
+///
+/// handles are opened lazily, see `Segment` for how each file is read,
+/// and closed once compaction moves the `safe_point` past them.
+#[derive(Clone)]
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    safe_point: Arc<AtomicU32>,
+    // id of the file being written, every other file is sealed
+    active_id: Arc<AtomicU32>,
+    keyring: Arc<Keyring>,
+    segments: Arc<RwLock<HashMap<FileId, Segment>>>,
+    cache: Option<Arc<ValueCache>>,
+}
+
+impl KvStoreReader {
+    fn new(
+        path: Arc<PathBuf>,
+        safe_point: Arc<AtomicU32>,
+        active_id: Arc<AtomicU32>,
+        keyring: Arc<Keyring>,
+        cache: Option<Arc<ValueCache>>,
+    ) -> Self {
+        Self {
+            path,
+            safe_point,
+            active_id,
+            keyring,
+            segments: Arc::default(),
+            cache,
+        }
+    }
+
+    // a reader of handles of its own, which are never closed, for a snapshot reading pinned files.
+    // the cache is shared, as a value is cached with the version of its write.
+    fn pinned(&self) -> Self {
+        Self::new(
+            self.path.clone(),
+            Arc::new(AtomicU32::new(0)),
+            self.active_id.clone(),
+            self.keyring.clone(),
+            self.cache.clone(),
+        )
+    }
+
+    fn close_stale_handles(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        self.segments
+            .write()
+            .unwrap()
+            .retain(|&file_id, _| file_id >= safe_point);
+    }
+
+    fn read_log(&self, meta: &LogMeta) -> KvsResult<Log> {
+        let sealed = meta.file_id != self.active_id.load(Ordering::SeqCst);
+        {
+            let segments = self.segments.read().unwrap();
+            match segments.get(&meta.file_id) {
+                // a file read while it was written is mapped once it is sealed
+                Some(segment) if segment.is_sealed() || !sealed => {
+                    return self.decode_log(segment, meta)
+                }
+                _ => (),
+            }
+        }
+        let segment = Segment::open(&self.path, meta.file_id, sealed)?;
+        let log = self.decode_log(&segment, meta);
+        self.segments.write().unwrap().insert(meta.file_id, segment);
+        log
+    }
+
+    fn decode_log(&self, segment: &Segment, meta: &LogMeta) -> KvsResult<Log> {
+        let buf = segment.read(meta.offset, meta.len)?;
+        decode_log(&buf, &self.keyring)
+            .map_err(|reason| corrupted(meta.file_id, meta.offset, reason))
+    }
+
+    // read the value of `key` through the cache, if there is one.
+    fn read_value(&self, key: &[u8], meta: &LogMeta) -> KvsResult<Option<Value>> {
+        let cache = self.cache.as_deref();
+        if let Some(value) = cache.and_then(|cache| cache.get(key, meta.version)) {
+            return Ok(Some(value));
+        }
+        let value = match &meta.blob {
+            Some(blob) => Some(blob::read_blob(&self.path, blob, &self.keyring)?),
+            None => self.read_log(meta)?.value,
+        };
+        if let (Some(cache), Some(value)) = (cache, &value) {
+            cache.insert(key.to_vec(), meta.version, value.clone());
+        }
+        Ok(value)
+    }
+}
+
+/// the single writer of a `KvStore`, shared by all clones behind a mutex.
+struct KvStoreWriter {
+    index: Arc<RwLock<Index>>,
+    path: Arc<PathBuf>,
+    manifest: Arc<Mutex<Manifest>>,
+    // ids of the files before `write_id`, in ascending order
+    file_ids: Vec<FileId>,
+    writer: File,
+    write_id: FileId,
+    // `write_id` as readers see it
+    active_id: Arc<AtomicU32>,
+    uncompact_size: usize,
+    // total length of the logs in the index
+    live_size: usize,
+    compactor: Compactor,
+    worker: CompactionWorker,
+    syncer: Arc<Syncer>,
+    interval_syncer: Option<IntervalSyncer>,
+    evicted: Arc<Evicted>,
+    deadlines: Arc<Deadlines>,
+    sweeper: Sweeper,
+    seq: Arc<AtomicU64>,
+    compressor: Arc<Compressor>,
+    keyring: Arc<Keyring>,
+    blobs: BlobFiles,
+    blob_gc: BlobGcWorker,
+    pins: Arc<Pins>,
+    cache: Option<Arc<ValueCache>>,
+    options: KvStoreOptions,
+}
+
+// `set` and `remove` return the sequence number of the write, to wait for it to be synced.
+impl KvStoreWriter {
+    fn set(&mut self, key: Key, value: Value, expires_at: Option<u64>) -> Result<u64> {
+        let entry = self.entry(key, value, expires_at)?;
+        self.write_entry(entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<u64> {
+        let found = match self.index.read().unwrap().get(key) {
+            Some(meta) => !meta.is_expired(now_ms()),
+            None => false,
+        };
+        if found {
+            self.write_entry(Entry::Log(Log {
+                key: key.to_vec(),
+                value: None,
+                expires_at: None,
+            }))
         } else {
-            Err(KvsError::KeyNotFound { key })
+            Err(KvsError::key_not_found(key))
+        }
+    }
+
+    fn write_entry(&mut self, entry: Entry) -> Result<u64> {
+        let (offset, len) = write_entry(&mut self.writer, &entry, &self.compressor, &self.keyring)
+            .inspect_err(|_| self.release_blobs(slice::from_ref(&entry)))?;
+        let seq = self.syncer.appended();
+        let meta = LogMeta {
+            file_id: self.write_id,
+            offset,
+            len,
+            expires_at: entry.expires_at(),
+            version: seq,
+            blob: entry.blob(),
+        };
+        self.index_entries([(entry, meta)], seq);
+        self.after_write(offset + len as u64)?;
+        Ok(seq)
+    }
+
+    // the entries of a batch are written in a single record and put into the index at once.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        let mut entries = Vec::with_capacity(batch.len());
+        for op in batch {
+            let entry = match op {
+                BatchOp::Set { key, value, ttl } => {
+                    self.entry(key, value, ttl.map(expiry::expires_at))
+                }
+                BatchOp::Remove { key } => Ok(Entry::Log(Log {
+                    key,
+                    value: None,
+                    expires_at: None,
+                })),
+            };
+            match entry {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    self.release_blobs(&entries);
+                    return Err(e);
+                }
+            }
+        }
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        let (offset, batch_len, positions) = self
+            .append_batch(&entries)
+            .inspect_err(|_| self.release_blobs(&entries))?;
+        let seq = self.syncer.appended();
+        let file_id = self.write_id;
+        let entries = entries
+            .into_iter()
+            .zip(positions)
+            .map(|(entry, (pos, len))| {
+                let meta = LogMeta {
+                    file_id,
+                    offset: offset + (record::HEADER_LEN + pos) as u64,
+                    len,
+                    expires_at: entry.expires_at(),
+                    version: seq,
+                    blob: entry.blob(),
+                };
+                (entry, meta)
+            })
+            .collect::<Vec<_>>();
+        self.index_entries(entries, seq);
+        self.after_write(offset + batch_len as u64)?;
+        Ok(seq)
+    }
+
+    // the values appended to blob files for entries which failed to be written are never pointed at.
+    fn release_blobs(&mut self, entries: &[Entry]) {
+        for blob in entries.iter().filter_map(Entry::blob) {
+            self.blobs.release(blob);
+        }
+    }
+
+    // write the entries in a batch record,
+    // return its offset and length, and the position and length of every entry in it.
+    fn append_batch(&mut self, entries: &[Entry]) -> KvsResult<(u64, usize, Positions)> {
+        let mut payload = Vec::new();
+        let mut positions = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            let buf = encode_entry(entry, &self.compressor, &self.keyring)?;
+            positions.push((payload.len(), buf.len()));
+            payload.extend_from_slice(&buf);
+        }
+        let (offset, len) = write_record(
+            &mut self.writer,
+            &record::encode(RecordType::Batch, &payload),
+        )?;
+        Ok((offset, len, positions))
+    }
+
+    // put the entries of the write `seq` into the index at once.
+    fn index_entries(&mut self, entries: impl IntoIterator<Item = (Entry, LogMeta)>, seq: u64) {
+        let index = self.index.clone();
+        let mut index = index.write().unwrap();
+        // the lengths of the logs in the index are only subtracted once the ones swapped in are counted
+        self.account_evicted();
+        for (entry, meta) in entries {
+            let len = meta.len;
+            let removes = entry.is_tombstone();
+            let (key, value) = entry.into_key_value();
+            // a written value replaces the cached one, a value in a blob file is cached once read
+            if let Some(cache) = &self.cache {
+                match value {
+                    Some(value) => cache.insert(key.clone(), meta.version, value),
+                    None => cache.remove(&key),
+                }
+            }
+            if let Some(expires_at) = index.get(&key).and_then(|old| old.expires_at) {
+                self.deadlines.remove(expires_at, &key);
+            }
+            let old = if removes {
+                // the key may have been swept since it was found
+                let old = index.remove(&key);
+                self.uncompact_size += len;
+                old
+            } else {
+                self.live_size += len;
+                if let Some(expires_at) = meta.expires_at {
+                    self.deadlines.insert(expires_at, key.clone());
+                }
+                index.insert(key, meta)
+            };
+            if let Some(old) = old {
+                self.uncompact_size += old.len;
+                self.live_size -= old.len;
+                if let Some(blob) = old.blob {
+                    self.blobs.release(blob);
+                }
+            }
         }
+        self.seq.store(seq, Ordering::SeqCst);
+    }
+
+    // `file_len` is the length of the file being written.
+    fn after_write(&mut self, file_len: u64) -> KvsResult<()> {
+        self.roll_trigger(file_len)?;
+        self.compaction_trigger()?;
+        self.blob_gc_trigger();
+        Ok(())
+    }
+
+    // compaction runs in the background, only one job at a time.
+    fn compaction_trigger(&mut self) -> KvsResult<()> {
+        self.account_evicted();
+        self.take_back_failed();
+        let total_size = self.uncompact_size + self.live_size;
+        if self.uncompact_size >= self.options.compaction_threshold
+            && self.uncompact_size as f64 >= self.options.garbage_ratio * total_size as f64
+            && !self.worker.is_running()
+        {
+            let job = self.seal()?;
+            self.worker.send(job);
+        }
+        Ok(())
+    }
+
+    // take in the logs taken out of the index by expiry and rewritten by compaction since last time.
+    fn account_evicted(&mut self) {
+        let garbage = self.evicted.garbage.swap(0, Ordering::SeqCst);
+        let dropped = self.evicted.dropped.swap(0, Ordering::SeqCst);
+        let rewritten = self.evicted.rewritten.swap(0, Ordering::SeqCst);
+        self.live_size = self.live_size.checked_add_signed(rewritten).unwrap() - garbage - dropped;
+        self.uncompact_size += garbage;
+        for blob in mem::take(&mut *self.evicted.blobs.lock().unwrap()) {
+            self.blobs.release(blob);
+        }
+    }
+
+    // the sealed files of a failed compaction are still live, so they are compacted with the next job.
+    fn take_back_failed(&mut self) {
+        let failed = mem::take(&mut *self.compactor.failed.lock().unwrap());
+        for job in failed {
+            self.file_ids.retain(|&id| id != job.compact_id);
+            self.file_ids.extend(job.file_ids);
+            self.file_ids.sort_unstable();
+            self.uncompact_size += job.uncompact_size;
+        }
+    }
+
+    // `file_len` is the length of the file being written.
+    fn roll_trigger(&mut self, file_len: u64) -> KvsResult<()> {
+        if file_len >= self.options.max_segment_size {
+            self.switch_to(self.write_id.wrapping_add(1))?;
+        }
+        Ok(())
+    }
+
+    // seal every file written so far and go on writing to a new file.
+    // the id between the sealed files and the new one is left for the compacted file.
+    fn seal(&mut self) -> KvsResult<CompactionJob> {
+        let compact_id = self.write_id.wrapping_add(1);
+        self.switch_to(self.write_id.wrapping_add(2))?;
+        Ok(CompactionJob {
+            compact_id,
+            file_ids: mem::replace(&mut self.file_ids, vec![compact_id]),
+            uncompact_size: mem::take(&mut self.uncompact_size),
+        })
+    }
+
+    // seal the file being written, which is never written again, and go on writing to a new file.
+    fn switch_to(&mut self, new_write_id: FileId) -> KvsResult<()> {
+        let new_writer = open_rw(&self.path, new_write_id)?;
+        if self.options.sync_policy != SyncPolicy::Never {
+            self.syncer.switch(&self.writer, &new_writer)?;
+        }
+        self.manifest.lock().unwrap().update(|files| {
+            files.insert(new_write_id);
+        })?;
+        self.writer = new_writer;
+        self.file_ids
+            .push(mem::replace(&mut self.write_id, new_write_id));
+        self.active_id.store(new_write_id, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// the writer shared by the clones of a store, which is closed when the last of them is dropped.
+/// blob GC only holds a `Weak` to the writer, so a store is always closed by the thread dropping it,
+/// and is closed once `drop` returns.
+struct SharedWriter(Arc<Mutex<KvStoreWriter>>);
+
+impl Deref for SharedWriter {
+    type Target = Mutex<KvStoreWriter>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
 }
 
-impl Drop for KvStore {
+impl Drop for SharedWriter {
     fn drop(&mut self) {
-        self.compaction_inner().ok();
+        let Ok(mut writer) = self.0.lock() else {
+            return;
+        };
+        let blob_gc = writer.blob_gc.stop();
+        drop(writer);
+        // blob GC takes the writer lock to swap in the file it is collecting
+        if let Some(blob_gc) = blob_gc {
+            blob_gc.join().ok();
+        }
+        if let Ok(mut writer) = self.0.lock() {
+            writer.close();
+        }
+    }
+}
+
+impl KvStoreWriter {
+    // stop the background work and compact the store, blob GC is stopped already.
+    fn close(&mut self) {
+        self.sweeper.shutdown();
+        self.worker.shutdown();
+        self.finish_rotation();
+        if let Some(interval_syncer) = self.interval_syncer.as_mut() {
+            interval_syncer.shutdown();
+        }
+        // the new write file left by sealing is empty, and `open` will create another one
+        if let Ok(job) = self.seal() {
+            if self.compactor.compact(job).is_ok() {
+                let write_id = self.write_id;
+                let removed = self.manifest.lock().unwrap().update(|files| {
+                    files.remove(&write_id);
+                });
+                if removed.is_ok() {
+                    remove_kvs_file(&self.path, write_id).ok();
+                }
+            }
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct Log {
+    #[serde(with = "serde_bytes")]
     pub key: Key,
+    #[serde(with = "serde_bytes")]
     pub value: Option<Value>,
+    /// milliseconds since the UNIX epoch, `None` if the key never expires
+    pub expires_at: Option<u64>,
+}
+
+/// a `Log` written in JSON by older versions.
+#[derive(Deserialize)]
+struct JsonLog {
+    key: String,
+    value: Option<String>,
+}
+
+impl From<JsonLog> for Log {
+    fn from(log: JsonLog) -> Self {
+        Self {
+            key: log.key.into_bytes(),
+            value: log.value.map(String::into_bytes),
+            expires_at: None,
+        }
+    }
+}
+
+/// what a record in a kvs file says about a key.
+pub(crate) enum Entry {
+    Log(Log),
+    /// the key is set to a value in a blob file
+    Blob(BlobLog),
+}
+
+impl Entry {
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Entry::Log(log) => log.expires_at,
+            Entry::Blob(log) => log.expires_at,
+        }
+    }
+
+    fn blob(&self) -> Option<BlobPointer> {
+        match self {
+            Entry::Log(_) => None,
+            Entry::Blob(log) => Some(log.blob),
+        }
+    }
+
+    fn is_tombstone(&self) -> bool {
+        matches!(self, Entry::Log(Log { value: None, .. }))
+    }
+
+    // the value is `None` for a tombstone and for a value in a blob file.
+    fn into_key_value(self) -> (Key, Option<Value>) {
+        match self {
+            Entry::Log(log) => (log.key, log.value),
+            Entry::Blob(log) => (log.key, None),
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) struct LogMeta {
     pub file_id: FileId,
     pub offset: u64,
     pub len: usize,
+    pub expires_at: Option<u64>,
+    /// sequence number of the write, 0 if it was written before the store was opened.
+    /// only kept in memory, to validate transactions.
+    pub version: u64,
+    /// where the value is if it is in a blob file, then the log is a `BlobLog`
+    pub blob: Option<BlobPointer>,
+}
+
+impl LogMeta {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 fn get_path(path: &Path, id: FileId) -> PathBuf {
     path.join(format!("{id}.kvs"))
 }
 
+// a kvs file and its hint file.
+fn kvs_file_paths(path: &Path, id: FileId) -> [PathBuf; 2] {
+    [get_path(path, id), hint::get_hint_path(path, id)]
+}
+
+// remove a kvs file and its hint file, if they exist.
+fn remove_kvs_file(path: &Path, id: FileId) -> KvsResult<()> {
+    for file in kvs_file_paths(path, id) {
+        remove_if_exists(&file)?;
+    }
+    Ok(())
+}
+
+fn remove_if_exists(file: &Path) -> io::Result<()> {
+    match remove_file(file) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// sync the dir at `path`, so the files renamed into it are there after a crash.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+// a dir cannot be opened as a file on Windows, where a rename is not synced through its dir.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
 fn open_ro(path: &Path, id: FileId) -> KvsResult<File> {
     let db_path = get_path(path, id);
     let db_file = OpenOptions::new().read(true).open(db_path)?;
@@ -245,17 +1204,77 @@ fn open_rw(path: &Path, id: FileId) -> KvsResult<File> {
     Ok(db_file)
 }
 
-fn read_log(file: &mut File, meta: &LogMeta) -> KvsResult<Log> {
-    file.seek(SeekFrom::Start(meta.offset))?;
-    let mut buf = vec![0; meta.len];
-    file.read_exact(&mut buf)?;
-    serde_json::de::from_slice(&buf).map_err(|e| e.into())
+// a record of an unknown key is not corrupted, the store is opened with the wrong key.
+fn corrupted(file_id: FileId, offset: u64, reason: Corruption) -> KvsError {
+    match reason {
+        Corruption::UnknownKey(key_id) => KvsError::UnknownKey { key_id },
+        reason => KvsError::Corrupted {
+            file_id,
+            offset,
+            reason,
+        },
+    }
+}
+
+fn decode_log(buf: &[u8], keyring: &Keyring) -> std::result::Result<Log, Corruption> {
+    let (t, payload) = record::decode(buf, keyring)?;
+    parse_log(t, &payload)
+}
+
+fn parse_log(t: RecordType, payload: &[u8]) -> std::result::Result<Log, Corruption> {
+    let invalid = |e: &dyn std::fmt::Display| Corruption::InvalidPayload(e.to_string());
+    match (t, payload) {
+        (RecordType::Log, payload) => bincode::deserialize(payload).map_err(|e| invalid(&e)),
+        (RecordType::JsonLog, payload) => serde_json::from_slice::<JsonLog>(payload)
+            .map(Log::from)
+            .map_err(|e| invalid(&e)),
+        (t, _) => Err(Corruption::UnexpectedType(t as u8)),
+    }
+}
+
+fn parse_entry(t: RecordType, payload: &[u8]) -> std::result::Result<Entry, Corruption> {
+    match t {
+        RecordType::BlobLog => bincode::deserialize(payload)
+            .map(Entry::Blob)
+            .map_err(|e| Corruption::InvalidPayload(e.to_string())),
+        t => parse_log(t, payload).map(Entry::Log),
+    }
+}
+
+// a `BlobLog` is small and only compressed along with the value.
+fn encode_entry(entry: &Entry, compressor: &Compressor, keyring: &Keyring) -> KvsResult<Vec<u8>> {
+    match entry {
+        Entry::Log(log) => {
+            let (payload, compressed) = compressor.compress(bincode::serialize(log)?);
+            Ok(record::encode_sealed(
+                RecordType::Log,
+                &payload,
+                compressed,
+                keyring,
+            ))
+        }
+        Entry::Blob(log) => Ok(record::encode_sealed(
+            RecordType::BlobLog,
+            &bincode::serialize(log)?,
+            false,
+            keyring,
+        )),
+    }
+}
+
+fn write_entry(
+    file: &mut File,
+    entry: &Entry,
+    compressor: &Compressor,
+    keyring: &Keyring,
+) -> KvsResult<(u64, usize)> {
+    write_record(file, &encode_entry(entry, compressor, keyring)?)
 }
 
-fn write_log(file: &mut File, log: &Log) -> KvsResult<(u64, usize)> {
-    let buf = serde_json::ser::to_vec(log)?;
+// append a whole record, return its offset and length.
+fn write_record(file: &mut File, buf: &[u8]) -> KvsResult<(u64, usize)> {
     let old_offset = file.seek(SeekFrom::End(0))?;
-    file.write_all(&buf)?;
+    file.write_all(buf)?;
     file.flush()?;
     Ok((old_offset, buf.len()))
 }
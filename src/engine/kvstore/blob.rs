@@ -0,0 +1,737 @@
+//! key/value separation: large values are kept out of the kvs files, WiscKey-style.
+//!
+//! a value of at least `KvStoreOptions::blob_threshold` bytes is appended to a blob file `<id>.blob`,
+//! in a `Blob` record holding the key and the value,
+//! and the kvs file only gets a small `BlobLog` record pointing at it.
+//! so compaction copies the pointers of large values, never the values themselves.
+//!
+//! the space of overwritten, removed and expired values is taken back by blob GC,
+//! which runs apart from compaction: once the garbage in the sealed blob files reaches
+//! `KvStoreOptions::blob_gc_threshold`, a worker copies the live values of the files with the most
+//! garbage into new blob files in the background, then takes the writer lock to write their new pointers
+//! to the kvs file and swap them into the index,
+//! and retires the old blob files, which are removed once no snapshot reads them.
+//!
+//! a blob file is rewritten by blob GC whatever its garbage if it holds records of a key other than
+//! `KvStoreOptions::encryption_key`, so rotating the key reaches the values too,
+//! while compaction only copies their pointers. `open` tells such files from the headers of their records,
+//! and the rest of them is rewritten when the store is dropped, see `KvStoreOptions::decryption_key`.
+//!
+//! a value is live as long as the index points at it, so blob files need no MANIFEST:
+//! `open` works out how much of each is live from the index, and removes the ones it does not point at.
+//! with `SyncPolicy::Always` a blob is synced before its pointer is written,
+//! and with `SyncPolicy::Interval` the active blob file is synced before the kvs file, see `sync`,
+//! so a synced pointer never outlives its value in a crash.
+use super::{
+    compression::Compressor,
+    encryption::Keyring,
+    expiry::now_ms,
+    record::{self, RecordError, RecordType},
+    sync::Syncer,
+    write_record, Entry, Index, Key, KvStoreWriter, LogMeta, SyncPolicy, Value,
+};
+use crate::error::{Corruption, KvsError, KvsResult};
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use std::{
+    collections::{btree_map, BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread::{self, JoinHandle},
+};
+
+pub(super) type BlobId = u32;
+
+/// where a value lives in a blob file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BlobPointer {
+    pub blob_id: BlobId,
+    pub offset: u64,
+    /// length of the whole `Blob` record
+    pub len: u32,
+}
+
+/// a `Log` setting a key to a value in a blob file.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BlobLog {
+    #[serde(with = "serde_bytes")]
+    pub key: Key,
+    pub blob: BlobPointer,
+    /// milliseconds since the UNIX epoch, `None` if the key never expires
+    pub expires_at: Option<u64>,
+}
+
+/// the payload of a record in a blob file.
+/// the key is kept next to the value so blob GC can tell whether the index still points at it.
+#[derive(Serialize, Deserialize)]
+struct Blob {
+    #[serde(with = "serde_bytes")]
+    key: Key,
+    #[serde(with = "serde_bytes")]
+    value: Value,
+}
+
+/// same as `Blob`, to encode without copying.
+#[derive(Serialize)]
+struct BlobRef<'a> {
+    #[serde(with = "serde_bytes")]
+    key: &'a [u8],
+    #[serde(with = "serde_bytes")]
+    value: &'a [u8],
+}
+
+pub(super) fn get_blob_path(path: &Path, id: BlobId) -> PathBuf {
+    path.join(format!("{id}.blob"))
+}
+
+/// encode a value into a record ready to be appended to a blob file.
+pub(super) fn encode_blob(
+    key: &[u8],
+    value: &[u8],
+    compressor: &Compressor,
+    keyring: &Keyring,
+) -> KvsResult<Vec<u8>> {
+    let (payload, compressed) = compressor.compress(bincode::serialize(&BlobRef { key, value })?);
+    Ok(record::encode_sealed(
+        RecordType::Blob,
+        &payload,
+        compressed,
+        keyring,
+    ))
+}
+
+fn decode_blob(buf: &[u8], keyring: &Keyring) -> Result<Blob, Corruption> {
+    match record::decode(buf, keyring)? {
+        (RecordType::Blob, payload) => {
+            bincode::deserialize(&payload).map_err(|e| Corruption::InvalidPayload(e.to_string()))
+        }
+        (t, _) => Err(Corruption::UnexpectedType(t as u8)),
+    }
+}
+
+// a blob of an unknown key is not corrupted, the store is opened with the wrong key.
+fn corrupted(blob_id: BlobId, offset: u64, reason: Corruption) -> KvsError {
+    match reason {
+        Corruption::UnknownKey(key_id) => KvsError::UnknownKey { key_id },
+        reason => KvsError::CorruptedBlob {
+            blob_id,
+            offset,
+            reason,
+        },
+    }
+}
+
+/// read the value `blob` points at.
+/// values in blob files are large, so the file is opened for every read rather than kept open.
+pub(super) fn read_blob(path: &Path, blob: &BlobPointer, keyring: &Keyring) -> KvsResult<Value> {
+    let mut file = File::open(get_blob_path(path, blob.blob_id))?;
+    file.seek(SeekFrom::Start(blob.offset))?;
+    let mut buf = vec![0; blob.len as usize];
+    file.read_exact(&mut buf)?;
+    decode_blob(&buf, keyring)
+        .map(|blob| blob.value)
+        .map_err(|reason| corrupted(blob.blob_id, blob.offset, reason))
+}
+
+#[derive(Clone, Copy, Default)]
+struct BlobUsage {
+    size: u64,
+    live: u64,
+}
+
+impl BlobUsage {
+    // `live` is only above `size` if the pointers past the end of the file are not dropped, see `drop_lost`
+    fn garbage(&self) -> u64 {
+        self.size.saturating_sub(self.live)
+    }
+}
+
+/// remove the keys whose value is past the end of its blob file, or in a missing one, from the index,
+/// and return the total length of their logs, which are garbage.
+/// unless a blob is synced before its pointer, a crash can keep the pointer and lose the value,
+/// and then the key is lost too.
+pub(super) fn drop_lost(path: &Path, index: &mut Index, logger: &Logger) -> KvsResult<usize> {
+    let mut sizes = BTreeMap::new();
+    let mut lost = Vec::new();
+    for (key, meta) in index.iter() {
+        let Some(blob) = meta.blob else {
+            continue;
+        };
+        let size = match sizes.entry(blob.blob_id) {
+            btree_map::Entry::Occupied(size) => *size.get(),
+            btree_map::Entry::Vacant(size) => {
+                *size.insert(match fs::metadata(get_blob_path(path, blob.blob_id)) {
+                    Ok(metadata) => metadata.len(),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                    Err(e) => return Err(e.into()),
+                })
+            }
+        };
+        if blob.offset + blob.len as u64 > size {
+            lost.push(key.clone());
+        }
+    }
+    let mut garbage = 0;
+    for key in lost {
+        let meta = index.remove(&key).unwrap();
+        let blob = meta.blob.unwrap();
+        warn!(logger, "dropping key whose value is lost from its blob file";
+            "key" => String::from_utf8_lossy(&key).into_owned(), "blob_id" => blob.blob_id, "offset" => blob.offset);
+        garbage += meta.len;
+    }
+    Ok(garbage)
+}
+
+/// the blob files of a store and how much of each is live, owned by the writer.
+pub(super) struct BlobFiles {
+    path: Arc<PathBuf>,
+    usage: BTreeMap<BlobId, BlobUsage>,
+    // the sealed blob files with records of an old key, or in plaintext while there is a key
+    stale: BTreeSet<BlobId>,
+    // the blob file being appended to, created for the first value written after `open` or a roll
+    active: Option<(BlobId, File)>,
+    next_id: BlobId,
+}
+
+impl BlobFiles {
+    /// find the blob files and how much of each the index points at.
+    /// the ones it does not point at are removed.
+    /// if there is an encryption key, the ones with records of another key are found too,
+    /// and one with records of a key not given fails with `KvsError::UnknownKey`.
+    pub fn open(path: Arc<PathBuf>, index: &Index, keyring: &Keyring) -> KvsResult<Self> {
+        let mut usage = BTreeMap::<BlobId, BlobUsage>::new();
+        for entry in fs::read_dir(&*path)? {
+            let file = entry?.path();
+            if file.extension() != Some("blob".as_ref()) {
+                continue;
+            }
+            if let Some(Ok(id)) = file.file_stem().and_then(OsStr::to_str).map(str::parse) {
+                usage.insert(
+                    id,
+                    BlobUsage {
+                        size: fs::metadata(&file)?.len(),
+                        live: 0,
+                    },
+                );
+            }
+        }
+        let next_id = usage.keys().next_back().map_or(1, |id| id + 1);
+        for blob in index.values().filter_map(|meta| meta.blob) {
+            if let Some(usage) = usage.get_mut(&blob.blob_id) {
+                usage.live += blob.len as u64;
+            }
+        }
+        let dead = usage
+            .iter()
+            .filter(|(_, usage)| usage.live == 0)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in dead {
+            fs::remove_file(get_blob_path(&path, id))?;
+            usage.remove(&id);
+        }
+        let mut stale = BTreeSet::new();
+        if let Some(current) = keyring.current() {
+            for &id in usage.keys() {
+                for key_id in record_keys(&get_blob_path(&path, id))? {
+                    match key_id {
+                        Some(key_id) if !keyring.contains(key_id) => {
+                            return Err(KvsError::UnknownKey { key_id })
+                        }
+                        Some(key_id) if key_id == current => {}
+                        _ => {
+                            stale.insert(id);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            path,
+            usage,
+            stale,
+            active: None,
+            next_id,
+        })
+    }
+
+    /// append a record made by `encode_blob` and return where it is.
+    /// the blob file is synced now if `sync`, or else by `syncer` along with the kvs file,
+    /// and sealed once it reaches `max_size`.
+    pub fn append(
+        &mut self,
+        record: &[u8],
+        sync: bool,
+        max_size: u64,
+        syncer: &Syncer,
+    ) -> KvsResult<BlobPointer> {
+        let (blob_id, file) = match &mut self.active {
+            Some(active) => active,
+            None => {
+                let id = self.next_id;
+                let file = OpenOptions::new()
+                    .create_new(true)
+                    .append(true)
+                    .open(get_blob_path(&self.path, id))?;
+                syncer.track_blob(Some(&file))?;
+                self.next_id += 1;
+                self.usage.insert(id, BlobUsage::default());
+                self.active.insert((id, file))
+            }
+        };
+        let blob_id = *blob_id;
+        let (offset, len) = write_record(file, record)?;
+        if sync {
+            file.sync_data()?;
+        }
+        let usage = self.usage.get_mut(&blob_id).unwrap();
+        usage.size += len as u64;
+        usage.live += len as u64;
+        if usage.size >= max_size {
+            // a sealed blob file is synced once and for all
+            if let Some((_, file)) = self.active.take() {
+                file.sync_data()?;
+                syncer.track_blob(None)?;
+            }
+        }
+        Ok(BlobPointer {
+            blob_id,
+            offset,
+            len: len as u32,
+        })
+    }
+
+    /// the sealed blob files, and the active one with its size.
+    pub fn files(&self) -> (Vec<BlobId>, Option<(BlobId, u64)>) {
+        let active = self
+            .active
+            .as_ref()
+            .map(|&(id, _)| (id, self.usage[&id].size));
+        let sealed = self
+            .usage
+            .keys()
+            .copied()
+            .filter(|&id| Some(id) != active.map(|(id, _)| id))
+            .collect();
+        (sealed, active)
+    }
+
+    /// an id for a blob file written by blob GC, which is not the active one.
+    pub fn reserve_id(&mut self) -> BlobId {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    /// the value `blob` points at is no longer live.
+    pub fn release(&mut self, blob: BlobPointer) {
+        if let Some(usage) = self.usage.get_mut(&blob.blob_id) {
+            usage.live -= blob.len as u64;
+        }
+    }
+
+    /// the blob file is collected, and its usage is no longer tracked.
+    fn remove(&mut self, blob_id: BlobId) {
+        self.usage.remove(&blob_id);
+        self.stale.remove(&blob_id);
+    }
+
+    /// the sealed blob files to collect: the stale ones, then the most garbage first,
+    /// so the garbage left in the others is below `threshold`.
+    fn victims(&self, threshold: u64) -> Vec<BlobId> {
+        let active = self.active.as_ref().map(|&(id, _)| id);
+        let mut sealed = self
+            .usage
+            .iter()
+            .filter(|(&id, _)| Some(id) != active)
+            .map(|(&id, usage)| (id, usage.garbage()))
+            .collect::<Vec<_>>();
+        let mut garbage = sealed.iter().map(|&(_, garbage)| garbage).sum::<u64>();
+        sealed.sort_unstable_by_key(|&(id, garbage)| {
+            (!self.stale.contains(&id), std::cmp::Reverse(garbage))
+        });
+        let mut victims = Vec::new();
+        for (id, file_garbage) in sealed {
+            if garbage < threshold && !self.stale.contains(&id) {
+                break;
+            }
+            garbage -= file_garbage;
+            victims.push(id);
+        }
+        victims
+    }
+}
+
+impl KvStoreWriter {
+    /// the entry setting `key`, with the value in a blob file if it is large enough.
+    pub(super) fn entry(
+        &mut self,
+        key: Key,
+        value: Value,
+        expires_at: Option<u64>,
+    ) -> KvsResult<Entry> {
+        match self.options.blob_threshold {
+            Some(threshold) if value.len() >= threshold => {
+                let record = encode_blob(&key, &value, &self.compressor, &self.keyring)?;
+                let sync = self.options.sync_policy == SyncPolicy::Always;
+                let blob = self.blobs.append(
+                    &record,
+                    sync,
+                    self.options.max_segment_size,
+                    &self.syncer,
+                )?;
+                Ok(Entry::Blob(BlobLog {
+                    key,
+                    blob,
+                    expires_at,
+                }))
+            }
+            _ => Ok(Entry::Log(super::Log {
+                key,
+                value: Some(value),
+                expires_at,
+            })),
+        }
+    }
+
+    /// collect the blob files with the most garbage once there is enough of it.
+    /// blob GC runs in the background, only one job at a time.
+    pub(super) fn blob_gc_trigger(&mut self) {
+        if self.blob_gc.is_running() {
+            return;
+        }
+        let victims = self.blobs.victims(self.options.blob_gc_threshold);
+        if !victims.is_empty() {
+            let jobs = victims
+                .into_iter()
+                .map(|blob_id| BlobGcJob {
+                    blob_id,
+                    output_id: self.blobs.reserve_id(),
+                })
+                .collect();
+            self.blob_gc.send(jobs);
+        }
+    }
+
+    /// rewrite the blob files left with records of an old key, as the store is dropped,
+    /// so the old key is not needed any more, like for the kvs files compacted then.
+    pub(super) fn finish_rotation(&mut self) {
+        let collector = BlobCollector {
+            path: self.path.clone(),
+            index: self.index.clone(),
+            compressor: self.compressor.clone(),
+            keyring: self.keyring.clone(),
+        };
+        for blob_id in self.blobs.stale.clone() {
+            let job = BlobGcJob {
+                blob_id,
+                output_id: self.blobs.reserve_id(),
+            };
+            let collected = collector
+                .collect(&job)
+                .inspect_err(|_| {
+                    fs::remove_file(get_blob_path(&self.path, job.output_id)).ok();
+                })
+                .and_then(|collected| self.swap_collected(collected));
+            if let Err(e) = collected {
+                warn!(self.options.logger, "blob GC failed"; "blob_id" => blob_id, "error" => %e);
+                break;
+            }
+        }
+    }
+
+    // write the pointers of the values moved by blob GC, point the index at them and retire the old file,
+    // return how many values were moved.
+    fn swap_collected(&mut self, collected: Collected) -> KvsResult<usize> {
+        let Collected {
+            blob_id,
+            output_id,
+            output_size,
+            moved,
+        } = collected;
+        let count = moved.len();
+        if !moved.is_empty() {
+            let (olds, entries): (Vec<_>, Vec<_>) = moved
+                .into_iter()
+                .map(|(old, log)| (old, Entry::Blob(log)))
+                .unzip();
+            let (offset, batch_len, positions) = match self.append_batch(&entries) {
+                Ok(appended) => appended,
+                Err(e) => {
+                    fs::remove_file(get_blob_path(&self.path, output_id)).ok();
+                    return Err(e);
+                }
+            };
+            self.blobs.usage.insert(
+                output_id,
+                BlobUsage {
+                    size: output_size,
+                    live: output_size,
+                },
+            );
+            // the pointers must be on disk before the old file is removed
+            self.writer.sync_data()?;
+            let file_id = self.write_id;
+            let mut index = self.index.write().unwrap();
+            for ((old, entry), (pos, len)) in olds.into_iter().zip(entries).zip(positions) {
+                let Entry::Blob(log) = entry else {
+                    unreachable!("only pointers are written by blob GC")
+                };
+                match index.get_mut(&log.key) {
+                    // the key may have been written or swept since,
+                    // while compaction only moves its `BlobLog`
+                    Some(meta) if meta.blob == Some(old) => {
+                        self.uncompact_size += meta.len;
+                        self.live_size = self.live_size + len - meta.len;
+                        *meta = LogMeta {
+                            file_id,
+                            offset: offset + (record::HEADER_LEN + pos) as u64,
+                            len,
+                            expires_at: meta.expires_at,
+                            // not a write of the key, so transactions see no conflict
+                            version: meta.version,
+                            blob: Some(log.blob),
+                        };
+                    }
+                    _ => {
+                        self.uncompact_size += len;
+                        self.blobs.release(log.blob);
+                    }
+                }
+            }
+            drop(index);
+            self.roll_trigger(offset + batch_len as u64)?;
+        }
+        self.blobs.remove(blob_id);
+        self.pins.retire(vec![get_blob_path(&self.path, blob_id)])?;
+        Ok(count)
+    }
+}
+
+// the keys the records of a blob file are encrypted with, `None` for the ones in plaintext.
+// only the header and the key id of each record are read.
+fn record_keys(path: &Path) -> KvsResult<BTreeSet<Option<u32>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = BTreeSet::new();
+    let mut buf = [0; record::HEADER_LEN + 4];
+    loop {
+        // a record torn by a crash at the end was never pointed at
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as i64;
+        keys.insert(record::key_id(&buf));
+        reader.seek_relative(len - (buf.len() - record::HEADER_LEN) as i64)?;
+    }
+    Ok(keys)
+}
+
+/// a sealed blob file to collect, and the id of the new blob file its live values are moved to.
+pub(super) struct BlobGcJob {
+    blob_id: BlobId,
+    output_id: BlobId,
+}
+
+/// the live values moved out of a blob file.
+struct Collected {
+    blob_id: BlobId,
+    output_id: BlobId,
+    output_size: u64,
+    // the old pointer of every value moved, with its new `BlobLog`
+    moved: Vec<(BlobPointer, BlobLog)>,
+}
+
+/// what blob GC needs to read the live values of a blob file, apart from the writer.
+pub(super) struct BlobCollector {
+    pub path: Arc<PathBuf>,
+    pub index: Arc<RwLock<Index>>,
+    pub compressor: Arc<Compressor>,
+    pub keyring: Arc<Keyring>,
+}
+
+impl BlobCollector {
+    // copy the live values of the blob file into the output file, which is synced,
+    // so the values are on disk before their pointers are written.
+    fn collect(&self, job: &BlobGcJob) -> KvsResult<Collected> {
+        let blob_id = job.blob_id;
+        let mut reader = BufReader::new(File::open(get_blob_path(&self.path, blob_id))?);
+        let mut output: Option<File> = None;
+        let mut output_size = 0;
+        let mut offset = 0;
+        let mut moved = Vec::new();
+        loop {
+            let buf = match record::read_record(&mut reader) {
+                Ok(Some(buf)) => buf,
+                // a blob torn by a crash was never pointed at
+                Ok(None) | Err(RecordError::Corrupted(Corruption::Truncated)) => break,
+                Err(RecordError::Io(e)) => return Err(e.into()),
+                Err(RecordError::Corrupted(reason)) => {
+                    return Err(corrupted(blob_id, offset, reason))
+                }
+            };
+            let old = BlobPointer {
+                blob_id,
+                offset,
+                len: buf.len() as u32,
+            };
+            offset += buf.len() as u64;
+            let blob = decode_blob(&buf, &self.keyring)
+                .map_err(|reason| corrupted(blob_id, old.offset, reason))?;
+            let expires_at = match self.index.read().unwrap().get(&blob.key) {
+                Some(meta) if meta.blob == Some(old) && !meta.is_expired(now_ms()) => {
+                    meta.expires_at
+                }
+                _ => continue,
+            };
+            // encoded again, so it is compressed and encrypted as the options say now
+            let record = encode_blob(&blob.key, &blob.value, &self.compressor, &self.keyring)?;
+            let file = match &mut output {
+                Some(file) => file,
+                None => output.insert(
+                    OpenOptions::new()
+                        .create_new(true)
+                        .append(true)
+                        .open(get_blob_path(&self.path, job.output_id))?,
+                ),
+            };
+            let (new_offset, len) = write_record(file, &record)?;
+            output_size += len as u64;
+            moved.push((
+                old,
+                BlobLog {
+                    key: blob.key,
+                    blob: BlobPointer {
+                        blob_id: job.output_id,
+                        offset: new_offset,
+                        len: len as u32,
+                    },
+                    expires_at,
+                },
+            ));
+        }
+        if let Some(file) = output {
+            file.sync_data()?;
+        }
+        Ok(Collected {
+            blob_id,
+            output_id: job.output_id,
+            output_size,
+            moved,
+        })
+    }
+}
+
+// collect the blob file of `job` and swap its values into the writer,
+// a failed collection leaves the blob file in place.
+fn collect(
+    collector: &BlobCollector,
+    writer: &Weak<Mutex<KvStoreWriter>>,
+    stopping: &AtomicBool,
+    job: &BlobGcJob,
+    logger: &Logger,
+) -> KvsResult<()> {
+    let output = get_blob_path(&collector.path, job.output_id);
+    // the store is being closed, and only waits for the job being collected
+    if stopping.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let collected = collector.collect(job).inspect_err(|_| {
+        fs::remove_file(&output).ok();
+    })?;
+    let moved = match writer.upgrade() {
+        Some(writer) => writer.lock().unwrap().swap_collected(collected)?,
+        None => {
+            // the moved values are never pointed at
+            fs::remove_file(&output).ok();
+            return Ok(());
+        }
+    };
+    info!(logger, "collected blob file"; "blob_id" => job.blob_id, "moved" => moved);
+    Ok(())
+}
+
+/// collects blob files in the background, like `CompactionWorker`.
+/// the values are copied without the writer lock,
+/// which is only taken to write their pointers and swap them into the index.
+pub(super) struct BlobGcWorker {
+    jobs: Option<Sender<Vec<BlobGcJob>>>,
+    handle: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl BlobGcWorker {
+    /// `writer` is weak, so the worker never holds the last handle to the writer,
+    /// which only the clones of the store do, see `SharedWriter`.
+    pub fn spawn(
+        collector: BlobCollector,
+        writer: Weak<Mutex<KvStoreWriter>>,
+        logger: Logger,
+    ) -> Self {
+        let (jobs, rx) = mpsc::channel::<Vec<BlobGcJob>>();
+        let running = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let running = running.clone();
+            let stopping = stopping.clone();
+            thread::spawn(move || {
+                for jobs in rx {
+                    let mut failed = false;
+                    for job in jobs {
+                        if let Err(e) = collect(&collector, &writer, &stopping, &job, &logger) {
+                            warn!(logger, "blob GC failed"; "blob_id" => job.blob_id, "error" => %e);
+                            failed = true;
+                            break;
+                        }
+                    }
+                    // the writes made while collecting may have left files over the threshold,
+                    // which no later write might trigger. a failed job waits for the next write.
+                    match writer.upgrade().filter(|_| !failed) {
+                        Some(writer) => {
+                            let mut writer = writer.lock().unwrap();
+                            running.store(false, Ordering::SeqCst);
+                            // a closing store has already stopped sending jobs
+                            if !stopping.load(Ordering::SeqCst) {
+                                writer.blob_gc_trigger();
+                            }
+                        }
+                        None => running.store(false, Ordering::SeqCst),
+                    }
+                }
+            })
+        };
+        Self {
+            jobs: Some(jobs),
+            handle: Some(handle),
+            running,
+            stopping,
+        }
+    }
+
+    /// whether a blob GC job is still running.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn send(&self, jobs: Vec<BlobGcJob>) {
+        self.running.store(true, Ordering::SeqCst);
+        // the worker only exits when `jobs` is dropped
+        self.jobs.as_ref().unwrap().send(jobs).unwrap();
+    }
+
+    /// stop the worker after the blob file being collected, and return its thread to wait for.
+    /// the worker takes the writer lock to swap in that file, so it must be waited for without the lock.
+    pub fn stop(&mut self) -> Option<JoinHandle<()>> {
+        self.stopping.store(true, Ordering::SeqCst);
+        drop(self.jobs.take());
+        self.handle.take()
+    }
+}
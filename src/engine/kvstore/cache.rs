@@ -0,0 +1,150 @@
+//! a bounded cache of the values read, in front of the kvs files.
+//!
+//! a value is cached with the version of the write which set it,
+//! and a lookup only hits if the version is the one in the index,
+//! so a value a read raced with a write never hides the newer one.
+//! the size of a cached value is the length of its key and value,
+//! and the least recently used values are evicted to stay within the capacity.
+use super::{Key, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// how the value cache has done since the store was opened, see `KvStoreOptions::value_cache`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// reads served by the cache
+    pub hits: u64,
+    /// reads of a value not in the cache
+    pub misses: u64,
+    /// values in the cache
+    pub entries: u64,
+    /// size of the keys and values in the cache, in bytes
+    pub size: u64,
+}
+
+impl CacheStats {
+    /// `hits / (hits + misses)`, 0.0 if nothing is read.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+/// the value cache shared by the readers, the writer and compaction.
+pub(super) struct ValueCache {
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lru: Mutex::new(Lru {
+                capacity,
+                ..Lru::default()
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// the value of `key` if it is cached as of the write `version`.
+    pub fn get(&self, key: &[u8], version: u64) -> Option<Value> {
+        let value = self.lru.lock().unwrap().get(key, version);
+        let counter = match value {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub fn insert(&self, key: Key, version: u64, value: Value) {
+        self.lru.lock().unwrap().insert(key, version, value);
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        self.lru.lock().unwrap().remove(key);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: lru.entries.len() as u64,
+            size: lru.size as u64,
+        }
+    }
+}
+
+struct Cached {
+    version: u64,
+    value: Value,
+    // when it was last used
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Key, Cached>,
+    // the keys by when they were last used, the least recently used first
+    recency: BTreeMap<u64, Key>,
+    tick: u64,
+    size: usize,
+    capacity: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &[u8], version: u64) -> Option<Value> {
+        let cached = self.entries.get_mut(key)?;
+        if cached.version != version {
+            return None;
+        }
+        self.tick += 1;
+        let key = self.recency.remove(&cached.tick).unwrap();
+        self.recency.insert(self.tick, key);
+        cached.tick = self.tick;
+        Some(cached.value.clone())
+    }
+
+    // a value larger than the whole cache is not cached, but still replaces the old one.
+    fn insert(&mut self, key: Key, version: u64, value: Value) {
+        self.remove(&key);
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        self.tick += 1;
+        self.size += size;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Cached {
+                version,
+                value,
+                tick: self.tick,
+            },
+        );
+        while self.size > self.capacity {
+            let (_, key) = self.recency.pop_first().unwrap();
+            let cached = self.entries.remove(&key).unwrap();
+            self.size -= key.len() + cached.value.len();
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(cached) = self.entries.remove(key) {
+            self.recency.remove(&cached.tick);
+            self.size -= key.len() + cached.value.len();
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! online checkpoints of a `KvStore` into another dir, taken by `KvsEngine::checkpoint`.
+//!
+//! a checkpoint is taken as of a write: with the writer locked, it lists the kvs files in the MANIFEST,
+//! the blob files and the lengths of the files being written, and pins them like a snapshot does,
+//! so neither compaction nor blob GC removes them in the meantime.
+//! writes go on while the files are put into the checkpoint:
+//! the sealed files and their hint files are never written again, so they are hard-linked,
+//! or copied if the checkpoint is on another file system,
+//! and the files being written are copied up to the lengths they had.
+//!
+//! every file is synced before the MANIFEST of the checkpoint is written,
+//! and the checkpoint is opened like any store, with the same keys if it is encrypted.
+use super::{blob, get_path, hint, manifest::Manifest, FileId, KvStore};
+use crate::error::{KvsError, KvsResult};
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+// a file to put into the checkpoint, with the length to copy if it is being written.
+struct Source {
+    path: PathBuf,
+    len: Option<u64>,
+}
+
+pub(super) fn checkpoint(store: &KvStore, dest: &Path) -> KvsResult<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::InvalidOption(format!(
+            "checkpoint dir `{}` is not empty",
+            dest.display()
+        )));
+    }
+    let path = &store.reader.path;
+    let _pin = store.pins.pin();
+    let (files, sources) = match &store.writer {
+        Some(writer) => {
+            let writer = writer.lock().unwrap();
+            let files = store.manifest.lock().unwrap().files().collect::<Vec<_>>();
+            let active = (writer.write_id, writer.writer.metadata()?.len());
+            let (blobs, active_blob) = writer.blobs.files();
+            let mut sources = kvs_sources(path, &files, Some(active));
+            sources.extend(blobs.into_iter().map(|id| Source {
+                path: blob::get_blob_path(path, id),
+                len: None,
+            }));
+            sources.extend(active_blob.map(|(id, len)| Source {
+                path: blob::get_blob_path(path, id),
+                len: Some(len),
+            }));
+            (files, sources)
+        }
+        // nothing is written, so every file is sealed
+        None => {
+            let files = store.manifest.lock().unwrap().files().collect::<Vec<_>>();
+            let mut sources = kvs_sources(path, &files, None);
+            for entry in fs::read_dir(&**path)? {
+                let file = entry?.path();
+                if file.extension() == Some("blob".as_ref()) {
+                    sources.push(Source {
+                        path: file,
+                        len: None,
+                    });
+                }
+            }
+            (files, sources)
+        }
+    };
+    for source in sources {
+        let file_name = source.path.file_name().expect("a file in the store dir");
+        let target = dest.join(file_name);
+        match source.len {
+            None => link_or_copy(&source.path, &target)?,
+            Some(len) => copy_prefix(&source.path, &target, len)?,
+        }
+        File::open(&target)?.sync_all()?;
+    }
+    Manifest::new(dest, files).update(|_| ())
+}
+
+// the kvs files in the MANIFEST and their hint files, with the length of the file being written.
+fn kvs_sources(path: &Path, files: &[FileId], active: Option<(FileId, u64)>) -> Vec<Source> {
+    let mut sources = Vec::new();
+    for &id in files {
+        let len = active
+            .filter(|&(active, _)| active == id)
+            .map(|(_, len)| len);
+        sources.push(Source {
+            path: get_path(path, id),
+            len,
+        });
+        let hint_path = hint::get_hint_path(path, id);
+        if hint_path.exists() {
+            sources.push(Source {
+                path: hint_path,
+                len: None,
+            });
+        }
+    }
+    sources
+}
+
+fn link_or_copy(source: &Path, target: &Path) -> io::Result<()> {
+    if fs::hard_link(source, target).is_err() {
+        fs::copy(source, target)?;
+    }
+    Ok(())
+}
+
+fn copy_prefix(source: &Path, target: &Path, len: u64) -> io::Result<()> {
+    let mut source = File::open(source)?.take(len);
+    io::copy(&mut source, &mut File::create(target)?)?;
+    Ok(())
+}
@@ -0,0 +1,282 @@
+//! compaction of the sealed kvs files, run in the background.
+//!
+//! when the writer decides to compact, it seals the file it is writing to
+//! and goes on writing to a new one, handing the sealed files to the compaction worker.
+//! the worker copies every live log of the sealed files into a single new file,
+//! then swaps the moved entries into the index and removes the sealed files.
+//! a value in a blob file is not copied, only the `BlobLog` pointing at it.
+//!
+//! if compaction fails before the MANIFEST lists the compacted file, the sealed files are still live:
+//! the compacted file is removed and the job is handed back to the writer,
+//! which compacts the sealed files again with the next job.
+use super::{
+    blob::BlobLog,
+    compression::Compressor,
+    expiry::{now_ms, Evicted},
+    hint, kvs_file_paths, open_rw, remove_kvs_file,
+    snapshot::Pins,
+    write_entry, Entry, FileId, Hint, Index, Key, KvStoreReader, LogMeta, Manifest,
+};
+use crate::error::{KvsError, KvsResult};
+use slog::{warn, Logger};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+};
+
+// what is written into the compacted file, to swap into the index.
+struct Compacted {
+    // the keys moved, with their old and new place
+    moved: Vec<(Key, LogMeta, LogMeta)>,
+    // the keys whose logs have expired and are not copied
+    expired: Vec<(Key, LogMeta)>,
+}
+
+/// a compaction to run.
+pub(super) struct CompactionJob {
+    /// id of the file the live logs are copied into.
+    /// it is larger than any sealed file and smaller than the file being written.
+    pub compact_id: FileId,
+    /// the sealed files, to be removed once compaction is done
+    pub file_ids: Vec<FileId>,
+    /// the garbage in the sealed files, as the writer counted it
+    pub uncompact_size: usize,
+}
+
+#[derive(Clone)]
+pub(super) struct Compactor {
+    index: Arc<RwLock<Index>>,
+    reader: KvStoreReader,
+    path: Arc<PathBuf>,
+    manifest: Arc<Mutex<Manifest>>,
+    evicted: Arc<Evicted>,
+    pins: Arc<Pins>,
+    compressor: Arc<Compressor>,
+    pub history: Arc<CompactionHistory>,
+    /// the jobs which failed, for the writer to take back
+    pub failed: Arc<Mutex<Vec<CompactionJob>>>,
+}
+
+impl Compactor {
+    pub fn new(
+        index: Arc<RwLock<Index>>,
+        reader: KvStoreReader,
+        path: Arc<PathBuf>,
+        manifest: Arc<Mutex<Manifest>>,
+        evicted: Arc<Evicted>,
+        pins: Arc<Pins>,
+        compressor: Arc<Compressor>,
+    ) -> Self {
+        Self {
+            index,
+            reader,
+            path,
+            manifest,
+            evicted,
+            pins,
+            compressor,
+            history: Arc::default(),
+            failed: Arc::default(),
+        }
+    }
+
+    // the index is only locked to take the entries to move and to swap the moved ones in,
+    // so reads and writes go on while the logs are copied.
+    pub fn compact(&self, job: CompactionJob) -> KvsResult<()> {
+        let Compacted { moved, expired } = match self.write_compacted(&job) {
+            Ok(written) => written,
+            Err(e) => {
+                remove_kvs_file(&self.path, job.compact_id).ok();
+                self.failed.lock().unwrap().push(job);
+                return Err(e);
+            }
+        };
+        let CompactionJob {
+            compact_id,
+            file_ids,
+            ..
+        } = job;
+        {
+            let mut index = self.index.write().unwrap();
+            let mut rewritten = 0;
+            for (key, old_meta, new_meta) in moved.into_iter() {
+                // the key may have been overwritten or removed since it was copied
+                match index.get_mut(&key) {
+                    Some(meta) if *meta == old_meta => {
+                        *meta = new_meta;
+                        rewritten += new_meta.len as isize - old_meta.len as isize;
+                    }
+                    _ => (),
+                }
+            }
+            self.evicted
+                .rewritten
+                .fetch_add(rewritten, Ordering::SeqCst);
+            for (key, old_meta) in expired.into_iter() {
+                if index.get(&key) == Some(&old_meta) {
+                    index.remove(&key);
+                    if let Some(cache) = &self.reader.cache {
+                        cache.remove(&key);
+                    }
+                    self.evicted
+                        .dropped
+                        .fetch_add(old_meta.len, Ordering::SeqCst);
+                    self.evicted.blobs.lock().unwrap().extend(old_meta.blob);
+                }
+            }
+            self.reader.safe_point.store(compact_id, Ordering::SeqCst);
+        }
+        self.history.record();
+        // readers hold the index lock while reading, so no one but snapshots is reading the sealed files now
+        self.reader.close_stale_handles();
+        let files = file_ids
+            .into_iter()
+            .flat_map(|id| kvs_file_paths(&self.path, id))
+            .collect();
+        self.pins.retire(files)
+    }
+
+    // copy the live logs into the compacted file and list it in the MANIFEST in place of the sealed files.
+    fn write_compacted(&self, job: &CompactionJob) -> KvsResult<Compacted> {
+        let compact_id = job.compact_id;
+        let mut compact_file = open_rw(&self.path, compact_id)?;
+        let entries = self
+            .index
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, meta)| meta.file_id < compact_id)
+            .map(|(key, &meta)| (key.clone(), meta))
+            .collect::<Vec<_>>();
+        let mut moved = Vec::with_capacity(entries.len());
+        let mut hints = Vec::with_capacity(entries.len());
+        // expired logs are not copied, and their keys are dropped from the index
+        let mut expired = Vec::new();
+        let now = now_ms();
+        for (key, meta) in entries.into_iter() {
+            if meta.is_expired(now) {
+                expired.push((key, meta));
+                continue;
+            }
+            let entry = match meta.blob {
+                Some(blob) => Entry::Blob(BlobLog {
+                    key: key.clone(),
+                    blob,
+                    expires_at: meta.expires_at,
+                }),
+                None => Entry::Log(self.reader.read_log(&meta)?),
+            };
+            // every copied log is compressed with the current codec and encrypted with the current key
+            let (offset, len) = write_entry(
+                &mut compact_file,
+                &entry,
+                &self.compressor,
+                &self.reader.keyring,
+            )?;
+            let new_meta = LogMeta {
+                file_id: compact_id,
+                offset,
+                len,
+                expires_at: meta.expires_at,
+                version: meta.version,
+                blob: meta.blob,
+            };
+            hints.push(Hint::new(key.clone(), &new_meta));
+            moved.push((key, meta, new_meta));
+        }
+        hint::write_hints(
+            &self.path,
+            compact_id,
+            compact_file.metadata()?.len(),
+            hints,
+            &self.reader.keyring,
+        )?;
+        // the compacted file must be on disk before the MANIFEST says it replaces the sealed ones
+        compact_file.sync_all()?;
+        self.manifest.lock().unwrap().update(|files| {
+            for id in job.file_ids.iter() {
+                files.remove(id);
+            }
+            files.insert(compact_id);
+        })?;
+
+        Ok(Compacted { moved, expired })
+    }
+}
+
+/// how many compactions have run since the store was opened, and when the last one finished.
+#[derive(Default)]
+pub(super) struct CompactionHistory {
+    runs: AtomicU64,
+    // milliseconds since the UNIX epoch, 0 if none has run
+    last_at: AtomicU64,
+}
+
+impl CompactionHistory {
+    fn record(&self) {
+        self.last_at.store(now_ms(), Ordering::SeqCst);
+        self.runs.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn runs(&self) -> u64 {
+        self.runs.load(Ordering::SeqCst)
+    }
+
+    pub fn last_at(&self) -> Option<u64> {
+        Some(self.last_at.load(Ordering::SeqCst)).filter(|&ms| ms > 0)
+    }
+}
+
+/// the background thread running compaction jobs sent by the writer, one at a time.
+pub(super) struct CompactionWorker {
+    jobs: Option<Sender<CompactionJob>>,
+    handle: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+}
+
+impl CompactionWorker {
+    pub fn spawn(compactor: Compactor, logger: Logger) -> Self {
+        let (jobs, rx) = mpsc::channel::<CompactionJob>();
+        let running = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let running = running.clone();
+            thread::spawn(move || {
+                for job in rx {
+                    if let Err(e) = compactor.compact(job) {
+                        warn!(logger, "{}", KvsError::CompactionError(e.to_string()));
+                    }
+                    running.store(false, Ordering::SeqCst);
+                }
+            })
+        };
+        Self {
+            jobs: Some(jobs),
+            handle: Some(handle),
+            running,
+        }
+    }
+
+    /// whether a compaction job is still running.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn send(&self, job: CompactionJob) {
+        self.running.store(true, Ordering::SeqCst);
+        // the worker only exits when `jobs` is dropped
+        self.jobs.as_ref().unwrap().send(job).unwrap();
+    }
+
+    /// wait for the running job to finish and stop the worker.
+    pub fn shutdown(&mut self) {
+        drop(self.jobs.take());
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
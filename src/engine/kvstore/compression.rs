@@ -0,0 +1,164 @@
+//! per-record compression of the logs.
+//!
+//! a compressed record has the `COMPRESSED` bit set in its type byte,
+//! and its payload is the id of the codec followed by the compressed bytes:
+//!
+//! ```text
+//! | codec: u8 | compressed payload |
+//! ```
+//!
+//! the checksum is over the bytes on disk, so a record is checked before it is decompressed.
+//! every record carries its own codec, so records of different codecs,
+//! or written with compression off, live side by side and the codec can be changed at any `open`.
+use crate::error::{Corruption, KvsError};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// the codec values are compressed with before they are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// write values as is.
+    #[default]
+    None,
+    /// LZ4, fast with a fair ratio.
+    Lz4,
+    /// Snappy, about as fast as LZ4.
+    Snappy,
+}
+
+// the id of every codec on disk, 0 is never written.
+const LZ4: u8 = 1;
+const SNAPPY: u8 = 2;
+
+impl Compression {
+    // return `None` if compression is off or the payload would not be any smaller.
+    fn compress(self, payload: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Compression::None => return None,
+            Compression::Lz4 => {
+                let mut buf = vec![LZ4];
+                buf.extend_from_slice(&lz4_flex::compress_prepend_size(payload));
+                buf
+            }
+            Compression::Snappy => {
+                let mut buf = vec![SNAPPY];
+                buf.extend_from_slice(&snap::raw::Encoder::new().compress_vec(payload).ok()?);
+                buf
+            }
+        };
+        (compressed.len() < payload.len()).then_some(compressed)
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Snappy => write!(f, "snappy"),
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = KvsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "lz4" => Ok(Self::Lz4),
+            "snappy" => Ok(Self::Snappy),
+            _ => Err(KvsError::InvalidOption(format!(
+                "invalid compression `{s}`, choose one of `none`, `lz4` or `snappy`"
+            ))),
+        }
+    }
+}
+
+/// decompress the payload of a record with the `COMPRESSED` bit set.
+pub(super) fn decompress(payload: &[u8]) -> Result<Vec<u8>, Corruption> {
+    let invalid = |e: &dyn std::fmt::Display| Corruption::InvalidPayload(e.to_string());
+    match payload.split_first() {
+        Some((&LZ4, compressed)) => {
+            lz4_flex::decompress_size_prepended(compressed).map_err(|e| invalid(&e))
+        }
+        Some((&SNAPPY, compressed)) => snap::raw::Decoder::new()
+            .decompress_vec(compressed)
+            .map_err(|e| invalid(&e)),
+        Some((codec, _)) => Err(Corruption::InvalidPayload(format!(
+            "unknown compression codec {codec}"
+        ))),
+        None => Err(Corruption::InvalidPayload(
+            "empty compressed payload".into(),
+        )),
+    }
+}
+
+/// how well the logs written since the store was opened have been compressed,
+/// including the ones copied by compaction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// logs written compressed
+    pub compressed_logs: u64,
+    /// logs written as is, because compression is off or would not make them smaller
+    pub uncompressed_logs: u64,
+    /// size of the compressed logs before compression, in bytes
+    pub raw_bytes: u64,
+    /// size of the compressed logs on disk, in bytes
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    /// `compressed_bytes / raw_bytes`, 1.0 if nothing is compressed.
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            1.0
+        } else {
+            self.compressed_bytes as f64 / self.raw_bytes as f64
+        }
+    }
+}
+
+/// compresses the logs with the codec of the options, shared by the writer and compaction.
+#[derive(Default)]
+pub(super) struct Compressor {
+    compression: Compression,
+    compressed_logs: AtomicU64,
+    uncompressed_logs: AtomicU64,
+    raw_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            ..Self::default()
+        }
+    }
+
+    /// compress the payload of a log, return whether it is compressed.
+    pub fn compress(&self, payload: Vec<u8>) -> (Vec<u8>, bool) {
+        match self.compression.compress(&payload) {
+            Some(compressed) => {
+                self.compressed_logs.fetch_add(1, Ordering::Relaxed);
+                self.raw_bytes
+                    .fetch_add(payload.len() as u64, Ordering::Relaxed);
+                self.compressed_bytes
+                    .fetch_add(compressed.len() as u64, Ordering::Relaxed);
+                (compressed, true)
+            }
+            None => {
+                self.uncompressed_logs.fetch_add(1, Ordering::Relaxed);
+                (payload, false)
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            compressed_logs: self.compressed_logs.load(Ordering::Relaxed),
+            uncompressed_logs: self.uncompressed_logs.load(Ordering::Relaxed),
+            raw_bytes: self.raw_bytes.load(Ordering::Relaxed),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
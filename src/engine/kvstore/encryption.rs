@@ -0,0 +1,187 @@
+//! authenticated encryption of the records holding user data, the logs and the hints.
+//!
+//! an encrypted record has the `ENCRYPTED` bit set in its type byte, and its payload is:
+//!
+//! ```text
+//! | key id: u32 | nonce: [u8; 12] | ciphertext and tag |
+//! ```
+//!
+//! records are encrypted with ChaCha20-Poly1305 under a random nonce,
+//! with the type byte as associated data so the flags cannot be tampered with either.
+//! a payload is compressed before it is encrypted.
+//!
+//! the key id tells which key a record is encrypted with, so records of an old key can still be read
+//! while new ones, including the ones copied by compaction, are encrypted with the current key.
+//! a record of a key not given is reported as `KvsError::UnknownKey` rather than as corruption.
+use crate::error::{Corruption, KvsError, KvsResult};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::collections::HashMap;
+
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+/// a 256-bit key to encrypt the records with, see `KvStoreOptions::encryption_key`.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    /// a key of the given bytes, which should come from a secure random source.
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+
+    /// read a key from the content of a key file, either 32 bytes as they are or 64 hex digits.
+    pub fn from_bytes(bytes: &[u8]) -> KvsResult<Self> {
+        if let Ok(key) = bytes.try_into() {
+            return Ok(Self(key));
+        }
+        match std::str::from_utf8(bytes) {
+            Ok(s) => s.trim().parse(),
+            Err(_) => Err(invalid_key()),
+        }
+    }
+
+    /// the id records encrypted with this key are tagged with.
+    /// it is derived from the key without revealing it.
+    pub fn id(&self) -> u32 {
+        key_id(&self.cipher())
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    // never print the key itself
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey({:08x})", self.id())
+    }
+}
+
+/// parse a key from 64 hex digits.
+impl std::str::FromStr for EncryptionKey {
+    type Err = KvsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != KEY_LEN * 2 || !s.is_ascii() {
+            return Err(invalid_key());
+        }
+        let mut key = [0; KEY_LEN];
+        for (byte, hex) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let hex = std::str::from_utf8(hex).unwrap();
+            *byte = u8::from_str_radix(hex, 16).map_err(|_| invalid_key())?;
+        }
+        Ok(Self(key))
+    }
+}
+
+fn invalid_key() -> KvsError {
+    KvsError::InvalidOption("an encryption key should be 32 bytes or 64 hex digits".to_owned())
+}
+
+// the first bytes of the tag of an empty message under a fixed nonce.
+// records use random nonces, which are never all zero in practice.
+fn key_id(cipher: &ChaCha20Poly1305) -> u32 {
+    let tag = cipher
+        .encrypt(
+            &Nonce::default(),
+            Payload {
+                msg: b"",
+                aad: b"kvs key id",
+            },
+        )
+        .expect("encrypting an empty message never fails");
+    u32::from_le_bytes(tag[..KEY_ID_LEN].try_into().unwrap())
+}
+
+/// the id of the key an encrypted payload is encrypted with, `None` if it is too short to tell.
+pub(super) fn payload_key_id(payload: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        payload.get(..KEY_ID_LEN)?.try_into().unwrap(),
+    ))
+}
+
+/// the keys of a store: the current one, which records are encrypted with, and the old ones.
+#[derive(Default)]
+pub(super) struct Keyring {
+    current: Option<u32>,
+    ciphers: HashMap<u32, ChaCha20Poly1305>,
+}
+
+impl Keyring {
+    pub fn new(current: Option<&EncryptionKey>, old: &[EncryptionKey]) -> Self {
+        let ciphers = current
+            .into_iter()
+            .chain(old)
+            .map(|key| {
+                let cipher = key.cipher();
+                (key_id(&cipher), cipher)
+            })
+            .collect();
+        Self {
+            current: current.map(EncryptionKey::id),
+            ciphers,
+        }
+    }
+
+    /// the id of the current key, `None` if records are written in plaintext.
+    pub fn current(&self) -> Option<u32> {
+        self.current
+    }
+
+    /// whether records encrypted with `key_id` can be decrypted.
+    pub fn contains(&self, key_id: u32) -> bool {
+        self.ciphers.contains_key(&key_id)
+    }
+
+    /// encrypt the payload of a record with the current key,
+    /// return `None` if there is no current key so the record is left in plaintext.
+    /// `record_type` is the type byte with the `ENCRYPTED` bit set.
+    pub fn encrypt(&self, record_type: u8, payload: &[u8]) -> Option<Vec<u8>> {
+        let key_id = self.current?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.ciphers[&key_id]
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: payload,
+                    aad: &[record_type],
+                },
+            )
+            .expect("encryption never fails for a payload under 4GiB");
+        let mut buf = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+        buf.extend_from_slice(&key_id.to_le_bytes());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&ciphertext);
+        Some(buf)
+    }
+
+    /// decrypt the payload of a record with the `ENCRYPTED` bit set in `record_type`.
+    pub fn decrypt(&self, record_type: u8, payload: &[u8]) -> Result<Vec<u8>, Corruption> {
+        if payload.len() < KEY_ID_LEN + NONCE_LEN {
+            return Err(Corruption::InvalidPayload(
+                "encrypted payload is too short".into(),
+            ));
+        }
+        let (key_id, rest) = payload.split_at(KEY_ID_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key_id = u32::from_le_bytes(key_id.try_into().unwrap());
+        let cipher = self
+            .ciphers
+            .get(&key_id)
+            .ok_or(Corruption::UnknownKey(key_id))?;
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[record_type],
+                },
+            )
+            .map_err(|_| Corruption::AuthenticationFailed)
+    }
+}
@@ -0,0 +1,141 @@
+//! expiration of the keys set with a TTL.
+//!
+//! a log set with a TTL carries the time it expires at, in milliseconds since the UNIX epoch.
+//! an expired key is invisible to reads right away(lazy expiry),
+//! and the sweeper takes it out of the index in the background so its log becomes garbage.
+//! no tombstone is written: replaying the expired log on `open` drops the key again,
+//! and compaction drops the expired log together with every older log of the key.
+//!
+//! the writer keeps the keys set with a TTL by when they expire,
+//! so the sweeper only visits the keys which are due rather than the whole index.
+use super::{blob::BlobPointer, cache::ValueCache, Index, Key};
+pub(super) use crate::engine::{expires_at, now_ms};
+use slog::{debug, Logger};
+use std::{
+    collections::BTreeSet,
+    mem,
+    sync::{
+        atomic::{AtomicIsize, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// lengths of the logs taken out of the index by expiry, for the writer to account for,
+/// and the change in length of the logs compaction rewrote.
+#[derive(Default)]
+pub(super) struct Evicted {
+    // taken out by the sweeper, their logs are still on disk as garbage
+    pub garbage: AtomicUsize,
+    // dropped by compaction, their logs are already gone
+    pub dropped: AtomicUsize,
+    // compaction rewrites the logs with the current codec and key, which may change their length
+    pub rewritten: AtomicIsize,
+    // the values in blob files of both
+    pub blobs: Mutex<Vec<BlobPointer>>,
+}
+
+/// the keys set with a TTL, by the time they expire at.
+/// an entry may be stale, as compaction drops expired keys without it,
+/// so the sweeper checks the index before taking a key out.
+#[derive(Default)]
+pub(super) struct Deadlines(Mutex<BTreeSet<(u64, Key)>>);
+
+impl Deadlines {
+    /// the deadlines of the keys of `index` set with a TTL.
+    pub fn new(index: &Index) -> Self {
+        let deadlines = index
+            .iter()
+            .filter_map(|(key, meta)| Some((meta.expires_at?, key.clone())))
+            .collect();
+        Self(Mutex::new(deadlines))
+    }
+
+    pub fn insert(&self, expires_at: u64, key: Key) {
+        self.0.lock().unwrap().insert((expires_at, key));
+    }
+
+    pub fn remove(&self, expires_at: u64, key: &[u8]) {
+        self.0.lock().unwrap().remove(&(expires_at, key.to_vec()));
+    }
+
+    // take out the deadlines up to `now`.
+    fn take_due(&self, now: u64) -> BTreeSet<(u64, Key)> {
+        let mut deadlines = self.0.lock().unwrap();
+        let later = deadlines.split_off(&(now + 1, Key::new()));
+        mem::replace(&mut *deadlines, later)
+    }
+}
+
+/// the background thread taking expired keys out of the index periodically.
+pub(super) struct Sweeper {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub fn spawn(
+        index: Arc<RwLock<Index>>,
+        deadlines: Arc<Deadlines>,
+        evicted: Arc<Evicted>,
+        cache: Option<Arc<ValueCache>>,
+        interval: Duration,
+        logger: Logger,
+    ) -> Self {
+        let (stop, rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            // the sender is only dropped on shutdown
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                let swept = sweep(&index, &deadlines, &evicted, cache.as_deref());
+                if swept > 0 {
+                    debug!(logger, "swept expired keys"; "count" => swept);
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+// take the expired keys out of the index and return how many there were.
+// the index is not locked at all if no key is due.
+fn sweep(
+    index: &RwLock<Index>,
+    deadlines: &Deadlines,
+    evicted: &Evicted,
+    cache: Option<&ValueCache>,
+) -> usize {
+    let now = now_ms();
+    let due = deadlines.take_due(now);
+    if due.is_empty() {
+        return 0;
+    }
+    let mut index = index.write().unwrap();
+    let mut swept = 0;
+    for (expires_at, key) in due {
+        // the key may have been set again or dropped by compaction since
+        match index.get(&key) {
+            Some(meta) if meta.expires_at == Some(expires_at) => (),
+            _ => continue,
+        }
+        let old_meta = index.remove(&key).unwrap();
+        if let Some(cache) = cache {
+            cache.remove(&key);
+        }
+        evicted.garbage.fetch_add(old_meta.len, Ordering::SeqCst);
+        evicted.blobs.lock().unwrap().extend(old_meta.blob);
+        swept += 1;
+    }
+    swept
+}
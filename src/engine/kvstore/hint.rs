@@ -0,0 +1,119 @@
+//! hint files, which let `KvStore::open` rebuild the index without reading any value.
+//!
+//! a hint file `<id>.hint` is written next to every file produced by compaction.
+//! it holds a single framed record listing where each key lives in `<id>.kvs`,
+//! together with the length of `<id>.kvs` when the hint was written.
+//! a hint whose length does not match its kvs file is stale and ignored.
+//! hints hold the keys, so they are encrypted like the logs.
+use super::{
+    blob::BlobPointer,
+    encryption::Keyring,
+    record::{self, RecordType},
+    FileId, Key, LogMeta,
+};
+use crate::error::{Corruption, KvsError, KvsResult};
+use serde::{Deserialize, Serialize};
+use slog::{warn, Logger};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// where a key lives in a kvs file.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct Hint {
+    #[serde(with = "serde_bytes")]
+    pub key: Key,
+    pub file_id: FileId,
+    pub offset: u64,
+    pub len: usize,
+    pub expires_at: Option<u64>,
+    pub blob: Option<BlobPointer>,
+}
+
+impl Hint {
+    pub fn new(key: Key, meta: &LogMeta) -> Self {
+        Self {
+            key,
+            file_id: meta.file_id,
+            offset: meta.offset,
+            len: meta.len,
+            expires_at: meta.expires_at,
+            blob: meta.blob,
+        }
+    }
+
+    pub fn meta(&self) -> LogMeta {
+        LogMeta {
+            file_id: self.file_id,
+            offset: self.offset,
+            len: self.len,
+            expires_at: self.expires_at,
+            version: 0,
+            blob: self.blob,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct HintFile {
+    file_len: u64,
+    hints: Vec<Hint>,
+}
+
+pub(super) fn get_hint_path(path: &Path, id: FileId) -> PathBuf {
+    path.join(format!("{id}.hint"))
+}
+
+/// write the hints of kvs file `id`, whose current length is `file_len`.
+pub(super) fn write_hints(
+    path: &Path,
+    id: FileId,
+    file_len: u64,
+    hints: Vec<Hint>,
+    keyring: &Keyring,
+) -> KvsResult<()> {
+    let payload = bincode::serialize(&HintFile { file_len, hints })?;
+    let mut file = File::create(get_hint_path(path, id))?;
+    file.write_all(&record::encode_sealed(
+        RecordType::Hint,
+        &payload,
+        false,
+        keyring,
+    ))?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// read the hints of kvs file `id`.
+/// return `Ok(None)` if there is no usable hint file, so the kvs file has to be replayed.
+pub(super) fn read_hints(
+    path: &Path,
+    id: FileId,
+    keyring: &Keyring,
+    logger: &Logger,
+) -> KvsResult<Option<Vec<Hint>>> {
+    let buf = match fs::read(get_hint_path(path, id)) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let hint_file = match record::decode(&buf, keyring) {
+        Ok((RecordType::Hint, payload)) => bincode::deserialize::<HintFile>(&payload).ok(),
+        Err(Corruption::UnknownKey(key_id)) => return Err(KvsError::UnknownKey { key_id }),
+        _ => None,
+    };
+    let file_len = fs::metadata(super::get_path(path, id))?.len();
+    match hint_file {
+        Some(hint_file) if hint_file.file_len == file_len => Ok(Some(hint_file.hints)),
+        Some(_) => {
+            warn!(logger, "ignoring stale hint file"; "file_id" => id);
+            Ok(None)
+        }
+        None => {
+            warn!(logger, "ignoring corrupted hint file"; "file_id" => id);
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! the in-memory index of a `KvStore`, an ordered map from every key to where its log is.
+//!
+//! the keys are kept in chunks of at most `MAX_CHUNK` keys, each behind an `Arc`,
+//! so cloning the index for a snapshot only clones the pointers to the chunks.
+//! a chunk shared with a snapshot is copied by the first write to it (copy on write),
+//! which costs at most `MAX_CHUNK` entries whatever the size of the index.
+use super::{Key, LogMeta};
+use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+
+/// the number of keys a chunk is split at.
+const MAX_CHUNK: usize = 1024;
+
+type Chunk = BTreeMap<Key, LogMeta>;
+
+/// every key lives in the chunk with the greatest first key not above it,
+/// and the first chunk starts at the empty key, so there is always one to insert into.
+#[derive(Clone)]
+pub(super) struct Index {
+    chunks: BTreeMap<Key, Arc<Chunk>>,
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self {
+            chunks: BTreeMap::from([(Key::new(), Arc::default())]),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&LogMeta> {
+        self.chunk(key).1.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut LogMeta> {
+        let start = self.chunk(key).0.clone();
+        let chunk = self.chunks.get_mut(&start).unwrap();
+        if !chunk.contains_key(key) {
+            return None;
+        }
+        Arc::make_mut(chunk).get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: Key, meta: LogMeta) -> Option<LogMeta> {
+        let start = self.chunk(&key).0.clone();
+        let chunk = Arc::make_mut(self.chunks.get_mut(&start).unwrap());
+        let old = chunk.insert(key, meta);
+        if chunk.len() > MAX_CHUNK {
+            let middle = chunk.keys().nth(chunk.len() / 2).unwrap().clone();
+            let upper = chunk.split_off(&middle);
+            self.chunks.insert(middle, Arc::new(upper));
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<LogMeta> {
+        let start = self.chunk(key).0.clone();
+        let chunk = self.chunks.get_mut(&start).unwrap();
+        if !chunk.contains_key(key) {
+            return None;
+        }
+        let old = Arc::make_mut(chunk).remove(key);
+        if chunk.is_empty() && !start.is_empty() {
+            self.chunks.remove(&start);
+        }
+        old
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &LogMeta)> {
+        self.chunks.values().flat_map(|chunk| chunk.iter())
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &LogMeta> {
+        self.iter().map(|(_, meta)| meta)
+    }
+
+    /// the keys from `start` to `end` and their logs, like `BTreeMap::range`.
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&'a [u8]>,
+        end: Bound<&'a [u8]>,
+    ) -> impl Iterator<Item = (&'a Key, &'a LogMeta)> {
+        // the chunk the range starts in, then the ones after it until the end of the range
+        let first = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self.chunk(key).0.clone(),
+            Bound::Unbounded => Key::new(),
+        };
+        self.chunks
+            .range(first..)
+            .take_while(move |(first, _)| match end {
+                Bound::Included(key) => first.as_slice() <= key,
+                Bound::Excluded(key) => first.as_slice() < key,
+                Bound::Unbounded => true,
+            })
+            .flat_map(move |(_, chunk)| chunk.range::<[u8], _>((start, end)))
+    }
+
+    // the chunk `key` lives in, with its first key.
+    fn chunk(&self, key: &[u8]) -> (&Key, &Arc<Chunk>) {
+        self.chunks
+            .range::<[u8], _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .expect("the first chunk starts at the empty key")
+    }
+}
@@ -0,0 +1,47 @@
+//! kvs files written before the records were framed, which hold bare JSON logs back to back.
+//!
+//! such a store has no MANIFEST, so its files are only checked when there is none.
+//! a file which starts with `{` but not with a whole record is rewritten
+//! into framed `JsonLog` records, into a temp file renamed over it,
+//! so a crash in between leaves either of them whole and the upgrade is done again on `open`.
+//! a read-only store cannot rewrite it, and refuses to open.
+use super::{
+    corrupted, get_path,
+    record::{self, RecordType},
+    sync_dir, FileId, JsonLog,
+};
+use crate::error::{Corruption, KvsError, KvsResult};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// rewrite the kvs file into framed records if it holds bare JSON logs.
+pub(super) fn upgrade(path: &Path, id: FileId, read_only: bool) -> KvsResult<()> {
+    let file_path = get_path(path, id);
+    let buf = fs::read(&file_path)?;
+    if buf.first() != Some(&b'{') || record::is_framed(&buf) {
+        return Ok(());
+    }
+    if read_only {
+        return Err(KvsError::InvalidOption(format!(
+            "kvs file `{}` predates framed records, open the store writable once to upgrade it",
+            file_path.display()
+        )));
+    }
+    let tmp_path = path.join(format!("{id}.kvs.tmp"));
+    let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+    let mut logs = serde_json::Deserializer::from_slice(&buf).into_iter::<JsonLog>();
+    let mut offset = 0;
+    while let Some(log) = logs.next() {
+        log.map_err(|e| corrupted(id, offset as u64, Corruption::InvalidPayload(e.to_string())))?;
+        let end = logs.byte_offset();
+        tmp.write_all(&record::encode(RecordType::JsonLog, &buf[offset..end]))?;
+        offset = end;
+    }
+    tmp.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&tmp_path, &file_path)?;
+    sync_dir(path)?;
+    Ok(())
+}
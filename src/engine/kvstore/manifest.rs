@@ -0,0 +1,89 @@
+//! the MANIFEST file, which records the set of live kvs files.
+//!
+//! a kvs file is only part of the db once it is listed in the MANIFEST,
+//! so a file compaction was writing when the process died is ignored on `open`.
+//! the MANIFEST is replaced atomically: the new content is written and synced to a temp file,
+//! which is then renamed over the old one before the dir itself is synced.
+//! it only lists file ids, so it is never encrypted.
+use super::{
+    encryption::Keyring,
+    record::{self, RecordType},
+    sync_dir, FileId,
+};
+use crate::error::{Corruption, KvsError, KvsResult};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct Manifest {
+    #[serde(skip)]
+    dir: PathBuf,
+    files: BTreeSet<FileId>,
+}
+
+impl Manifest {
+    /// a manifest of the given files, not persisted yet.
+    pub fn new(dir: &Path, files: impl IntoIterator<Item = FileId>) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            files: files.into_iter().collect(),
+        }
+    }
+
+    /// load the manifest in `dir`, return `Ok(None)` if there is none.
+    pub fn load(dir: &Path) -> KvsResult<Option<Self>> {
+        let buf = match fs::read(dir.join(MANIFEST)) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut manifest =
+            match record::decode(&buf, &Keyring::default()).map_err(KvsError::CorruptedManifest)? {
+                (RecordType::Manifest, payload) => serde_json::from_slice::<Self>(&payload)
+                    .map_err(|e| {
+                        KvsError::CorruptedManifest(Corruption::InvalidPayload(e.to_string()))
+                    })?,
+                (t, _) => {
+                    return Err(KvsError::CorruptedManifest(Corruption::UnexpectedType(
+                        t as u8,
+                    )))
+                }
+            };
+        manifest.dir = dir.to_path_buf();
+        Ok(Some(manifest))
+    }
+
+    /// the live files, in ascending order.
+    pub fn files(&self) -> impl Iterator<Item = FileId> + '_ {
+        self.files.iter().copied()
+    }
+
+    /// change the set of live files and persist it.
+    /// the set is left as it was if it fails to be persisted.
+    pub fn update(&mut self, f: impl FnOnce(&mut BTreeSet<FileId>)) -> KvsResult<()> {
+        let mut updated = Self::new(&self.dir, self.files());
+        f(&mut updated.files);
+        updated.persist()?;
+        *self = updated;
+        Ok(())
+    }
+
+    fn persist(&self) -> KvsResult<()> {
+        let buf = record::encode(RecordType::Manifest, &serde_json::to_vec(self)?);
+        let tmp_path = self.dir.join(MANIFEST_TMP);
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&buf)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(MANIFEST))?;
+        sync_dir(&self.dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! options to open a `KvStore` with.
+use super::{Compression, EncryptionKey};
+use crate::error::KvsError;
+use slog::{o, Discard, Logger};
+use std::time::Duration;
+
+/// by default, a compaction is triggered when the invalid size is larger than this(in bytes).
+const DEFAULT_COMPACTION_THRESHOLD: usize = 4 * 1024 * 1024;
+/// by default, the kvs file being written(the active segment) is sealed when it is larger than this(in bytes).
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+/// by default, blob GC is triggered when the garbage in blob files is larger than this(in bytes).
+const DEFAULT_BLOB_GC_THRESHOLD: u64 = 64 * 1024 * 1024;
+/// by default, expired keys are swept out of the index this often.
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// when the written logs are made durable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// `fsync` after every write, so a successful write survives a power failure.
+    /// concurrent writes share a single `fsync`.
+    Always,
+    /// `fsync` every given milliseconds in the background.
+    /// a power failure loses at most the writes of the last interval.
+    Interval(u64),
+    /// leave it to the OS. a successful write survives a crash of the process, but not of the machine.
+    #[default]
+    Never,
+}
+
+impl std::fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::Interval(ms) => write!(f, "interval:{ms}"),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl std::str::FromStr for SyncPolicy {
+    type Err = KvsError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "always" => Ok(Self::Always),
+            None if s == "never" => Ok(Self::Never),
+            Some(("interval", ms)) if ms.parse::<u64>().is_ok_and(|ms| ms > 0) => {
+                Ok(Self::Interval(ms.parse().unwrap()))
+            }
+            _ => Err(KvsError::InvalidOption(format!(
+                "invalid sync policy `{s}`, choose one of `always`, `never` or `interval:<ms>`"
+            ))),
+        }
+    }
+}
+
+/// options to open a `KvStore` with.
+///
+/// ```no_run
+/// # use kvs::engine::kvstore::{KvStoreOptions, SyncPolicy};
+/// # use kvs::KvStore;
+/// let store = KvStore::open_with(
+///     "db",
+///     KvStoreOptions::new()
+///         .compaction_threshold(1024 * 1024)
+///         .sync_policy(SyncPolicy::Always),
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) compaction_threshold: usize,
+    pub(super) garbage_ratio: f64,
+    pub(super) max_segment_size: u64,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_only: bool,
+    pub(super) sweep_interval: Duration,
+    pub(super) compression: Compression,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    pub(super) decryption_keys: Vec<EncryptionKey>,
+    pub(super) blob_threshold: Option<usize>,
+    pub(super) blob_gc_threshold: u64,
+    pub(super) value_cache: Option<usize>,
+    pub(super) logger: Logger,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            garbage_ratio: 0.0,
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            sync_policy: SyncPolicy::default(),
+            read_only: false,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            compression: Compression::default(),
+            encryption_key: None,
+            decryption_keys: Vec::new(),
+            blob_threshold: None,
+            blob_gc_threshold: DEFAULT_BLOB_GC_THRESHOLD,
+            value_cache: None,
+            logger: Logger::root(Discard, o!()),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// compaction is only triggered when the invalid size is at least `bytes`.
+    /// default to 4MiB.
+    pub fn compaction_threshold(mut self, bytes: usize) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// compaction is only triggered when the invalid size is at least `ratio` of the size of all files.
+    /// default to 0, which means only `compaction_threshold` is checked.
+    ///
+    /// # Panics
+    /// panics if `ratio` is not in `0.0..=1.0`.
+    pub fn garbage_ratio(mut self, ratio: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&ratio),
+            "garbage ratio should be in 0.0..=1.0"
+        );
+        self.garbage_ratio = ratio;
+        self
+    }
+
+    /// the kvs file being written(the active segment) is sealed when it reaches `bytes`,
+    /// and writes go on in a new file.
+    /// default to 64MiB.
+    pub fn max_segment_size(mut self, bytes: u64) -> Self {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// when the written logs are made durable. default to `SyncPolicy::Never`.
+    ///
+    /// # Panics
+    /// panics if the interval of `SyncPolicy::Interval` is 0.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        assert!(
+            policy != SyncPolicy::Interval(0),
+            "sync interval should be larger than 0"
+        );
+        self.sync_policy = policy;
+        self
+    }
+
+    /// open the store read-only: nothing on disk is changed, and every write fails.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// how often expired keys are swept out of the index in the background.
+    /// they are invisible to reads as soon as they expire anyway.
+    /// default to 1 second.
+    ///
+    /// # Panics
+    /// panics if `interval` is 0.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        assert!(
+            !interval.is_zero(),
+            "sweep interval should be larger than 0"
+        );
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// the codec the logs are compressed with when written, including by compaction.
+    /// a log which compression would not make smaller is written as is.
+    /// files written with any codec can be read whatever this is.
+    /// default to `Compression::None`.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// encrypt the logs, hints and values in blob files with `key` when they are written,
+    /// including by compaction and blob GC,
+    /// and decrypt the ones written with it. records written before without a key stay readable.
+    /// opening a store with records of a key neither given here nor by `decryption_key`
+    /// fails with `KvsError::UnknownKey`.
+    /// default to no encryption.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// an old key, only to decrypt the records written with it.
+    /// may be called more than once.
+    ///
+    /// to rotate keys, open the store with the new key as `encryption_key` and the old one here:
+    /// compaction rewrites the records with the new key, and blob GC the blob files with values
+    /// of the old one, from when the store is opened. the old key is not needed any more
+    /// once the store is dropped, which compacts it and rewrites the blob files left.
+    pub fn decryption_key(mut self, key: EncryptionKey) -> Self {
+        self.decryption_keys.push(key);
+        self
+    }
+
+    /// store values of at least `bytes` in blob files apart from the keys,
+    /// so compaction does not copy them. default to keep every value with its key.
+    pub fn blob_threshold(mut self, bytes: usize) -> Self {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    /// blob GC is triggered when the garbage in the sealed blob files is at least `bytes`.
+    /// default to 64MiB.
+    pub fn blob_gc_threshold(mut self, bytes: u64) -> Self {
+        self.blob_gc_threshold = bytes;
+        self
+    }
+
+    /// cache up to `bytes` of the values read and written, counting their keys,
+    /// and evict the least recently used ones beyond that.
+    /// see `KvStore::cache_stats` for how it does. default to no cache.
+    pub fn value_cache(mut self, bytes: usize) -> Self {
+        self.value_cache = Some(bytes);
+        self
+    }
+
+    /// where to report what happens in the background, like recovery and compaction.
+    /// default to discard everything.
+    pub fn logger(mut self, logger: Logger) -> Self {
+        self.logger = logger;
+        self
+    }
+}
@@ -0,0 +1,251 @@
+//! the framed record format used by the kvs log files.
+//!
+//! every record on disk looks like this (integers are little-endian):
+//!
+//! ```text
+//! | len: u32 | crc: u32 | type: u8 | payload: [u8; len] |
+//! ```
+//!
+//! `crc` is the crc32 of `type` and `payload`,
+//! so a flipped bit or a partial write is detected before the payload is parsed.
+//!
+//! the high bit of `type` is set if the payload is compressed, see `compression`,
+//! and the next one if it is encrypted, see `encryption`.
+//!
+//! the payload of a batch record is a run of whole `Log` records,
+//! so a torn batch fails its outer checksum and is discarded as a whole,
+//! while each `Log` in it can still be read on its own.
+use super::{
+    compression,
+    encryption::{self, Keyring},
+};
+use crate::error::Corruption;
+use std::{
+    borrow::Cow,
+    io::{self, Read},
+};
+
+/// length of the record header in bytes.
+pub(super) const HEADER_LEN: usize = 9;
+
+/// the bit of the type byte telling the payload is compressed.
+const COMPRESSED: u8 = 0x80;
+/// the bit of the type byte telling the payload is encrypted.
+const ENCRYPTED: u8 = 0x40;
+
+/// the kind of payload a record carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub(super) enum RecordType {
+    /// a single `Log` in JSON, written by older versions which only took UTF-8 keys and values
+    JsonLog = 1,
+    // 2 is reserved, it was taken by hints in JSON, which no release has written
+    /// the content of the MANIFEST
+    Manifest = 3,
+    /// a single `Log` in bincode
+    Log = 4,
+    /// the content of a hint file in bincode
+    Hint = 5,
+    /// `Log` records written atomically
+    Batch = 6,
+    /// a `BlobLog`, setting a key to a value in a blob file
+    BlobLog = 7,
+    /// a value in a blob file, with its key
+    Blob = 8,
+}
+
+impl TryFrom<u8> for RecordType {
+    type Error = Corruption;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::JsonLog),
+            3 => Ok(Self::Manifest),
+            4 => Ok(Self::Log),
+            5 => Ok(Self::Hint),
+            6 => Ok(Self::Batch),
+            7 => Ok(Self::BlobLog),
+            8 => Ok(Self::Blob),
+            t => Err(Corruption::UnknownType(t)),
+        }
+    }
+}
+
+/// errors met when reading a record.
+#[derive(Debug)]
+pub(super) enum RecordError {
+    Io(io::Error),
+    Corrupted(Corruption),
+}
+
+impl From<io::Error> for RecordError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<Corruption> for RecordError {
+    fn from(c: Corruption) -> Self {
+        Self::Corrupted(c)
+    }
+}
+
+fn checksum(record_type: u8, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[record_type]);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// frame the payload into a record, ready to be appended to a log file.
+pub(super) fn encode(record_type: RecordType, payload: &[u8]) -> Vec<u8> {
+    frame(record_type as u8, payload)
+}
+
+/// same as `encode`, for a record of user data which is encrypted if the keyring has a current key.
+/// `compressed` tells the payload is compressed by `Compressor`.
+pub(super) fn encode_sealed(
+    record_type: RecordType,
+    payload: &[u8],
+    compressed: bool,
+    keyring: &Keyring,
+) -> Vec<u8> {
+    let record_type = if compressed {
+        record_type as u8 | COMPRESSED
+    } else {
+        record_type as u8
+    };
+    match keyring.encrypt(record_type | ENCRYPTED, payload) {
+        Some(encrypted) => frame(record_type | ENCRYPTED, &encrypted),
+        None => frame(record_type, payload),
+    }
+}
+
+fn frame(record_type: u8, payload: &[u8]) -> Vec<u8> {
+    let len = u32::try_from(payload.len()).expect("record payload larger than 4GiB");
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&checksum(record_type, payload).to_le_bytes());
+    buf.push(record_type);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// check a whole record read from disk and return its type and payload,
+/// decrypted with the keyring and decompressed if it was.
+pub(super) fn decode<'a>(
+    buf: &'a [u8],
+    keyring: &Keyring,
+) -> Result<(RecordType, Cow<'a, [u8]>), Corruption> {
+    if buf.len() < HEADER_LEN {
+        return Err(Corruption::Truncated);
+    }
+    let (header, payload) = buf.split_at(HEADER_LEN);
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let record_type = header[8];
+    if len != payload.len() {
+        return Err(Corruption::LengthMismatch);
+    }
+    if crc != checksum(record_type, payload) {
+        return Err(Corruption::ChecksumMismatch);
+    }
+    let mut payload = Cow::Borrowed(payload);
+    if record_type & ENCRYPTED != 0 {
+        payload = Cow::Owned(keyring.decrypt(record_type, &payload)?);
+    }
+    if record_type & COMPRESSED != 0 {
+        payload = Cow::Owned(compression::decompress(&payload)?);
+    }
+    Ok((
+        (record_type & !(COMPRESSED | ENCRYPTED)).try_into()?,
+        payload,
+    ))
+}
+
+/// whether `buf` starts with a whole record whose checksum matches, whatever its payload is.
+pub(super) fn is_framed(buf: &[u8]) -> bool {
+    let Some(header) = buf.get(..HEADER_LEN) else {
+        return false;
+    };
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    buf.get(HEADER_LEN..HEADER_LEN + len)
+        .is_some_and(|payload| crc == checksum(header[8], payload))
+}
+
+/// whether `header`, the first bytes of a record with `available` bytes left in the file,
+/// can be the start of a record torn by a crash: a header too short to tell,
+/// or one of a known type claiming more bytes than are left.
+pub(super) fn is_torn(header: &[u8], available: u64) -> bool {
+    if header.len() < HEADER_LEN {
+        return true;
+    }
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as u64;
+    let known = RecordType::try_from(header[8] & !(COMPRESSED | ENCRYPTED)).is_ok();
+    known && available < HEADER_LEN as u64 + len
+}
+
+/// the id of the key the record starting with `buf` is encrypted with, `None` if it is in plaintext.
+/// `buf` holds the header and the start of the payload, see `encryption`.
+pub(super) fn key_id(buf: &[u8]) -> Option<u32> {
+    let record_type = *buf.get(HEADER_LEN - 1)?;
+    if record_type & ENCRYPTED == 0 {
+        return None;
+    }
+    encryption::payload_key_id(&buf[HEADER_LEN..])
+}
+
+/// split the payload of a batch record into the records in it,
+/// with their offsets in the payload.
+pub(super) fn split_batch(payload: &[u8]) -> Result<Vec<(usize, &[u8])>, Corruption> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < payload.len() {
+        let header = payload
+            .get(pos..pos + HEADER_LEN)
+            .ok_or(Corruption::LengthMismatch)?;
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let end = pos + HEADER_LEN + len;
+        let record = payload.get(pos..end).ok_or(Corruption::LengthMismatch)?;
+        records.push((pos, record));
+        pos = end;
+    }
+    Ok(records)
+}
+
+/// read the next record from `reader`.
+/// return `Ok(None)` if the reader is at a clean end of file.
+pub(super) fn read_record(reader: &mut impl Read) -> Result<Option<Vec<u8>>, RecordError> {
+    let mut buf = vec![0; HEADER_LEN];
+    let read = read_full(reader, &mut buf)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read < HEADER_LEN {
+        return Err(Corruption::Truncated.into());
+    }
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    // don't trust `len` for allocation, it may be garbage
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() < HEADER_LEN + len {
+        return Err(Corruption::Truncated.into());
+    }
+    Ok(Some(buf))
+}
+
+// like `read_exact`, but return how many bytes were read before end of file.
+fn read_full(reader: &mut impl Read, mut buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while !buf.is_empty() {
+        match reader.read(buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                read += n;
+                buf = &mut buf[n..];
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
@@ -0,0 +1,73 @@
+//! read handles to the kvs files.
+//!
+//! a sealed file is never written again, so it is mapped into memory once
+//! and a read is a copy out of the page cache, without any syscall.
+//! the file being written grows under the readers, so it is read with positional reads instead,
+//! which leave no file offset to contend on either.
+use super::{get_path, FileId};
+use crate::error::KvsResult;
+use memmap2::Mmap;
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+};
+
+pub(super) enum Segment {
+    Sealed(Mmap),
+    Active(File),
+}
+
+impl Segment {
+    pub fn open(path: &Path, id: FileId, sealed: bool) -> KvsResult<Self> {
+        let file = OpenOptions::new().read(true).open(get_path(path, id))?;
+        if !sealed {
+            return Ok(Self::Active(file));
+        }
+        // SAFETY: a sealed file is neither written nor truncated any more,
+        // and removing it leaves the mapping valid until it is dropped.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self::Sealed(map))
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        matches!(self, Self::Sealed(_))
+    }
+
+    /// read `len` bytes at `offset`, borrowed from the mapping if the file is sealed.
+    pub fn read(&self, offset: u64, len: usize) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            Self::Sealed(map) => usize::try_from(offset)
+                .ok()
+                .and_then(|start| map.get(start..start.checked_add(len)?))
+                .map(Cow::Borrowed)
+                .ok_or_else(|| io::ErrorKind::UnexpectedEof.into()),
+            Self::Active(file) => {
+                let mut buf = vec![0; len];
+                read_exact_at(file, &mut buf, offset)?;
+                Ok(Cow::Owned(buf))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,196 @@
+//! read-only snapshots of a `KvStore`.
+//!
+//! a snapshot holds the index as of some write, sharing the chunks no write has changed since,
+//! so it keeps seeing the values of that time while writes go on.
+//! the files it refers to may be compacted away in the meantime,
+//! so it pins them: compaction and blob GC hand their obsolete files to `Pins`,
+//! which only removes them once every snapshot older than the compaction is dropped.
+use super::{expiry::now_ms, remove_if_exists, Index, Key, KvStoreReader};
+use crate::{
+    engine::{KvPairs, Result},
+    error::KvsResult,
+};
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// a read-only view of a `KvStore` as of a write, taken by `KvStore::snapshot`.
+///
+/// the files the snapshot reads from are kept on disk until it is dropped.
+pub struct Snapshot {
+    state: Arc<SnapshotState>,
+}
+
+struct SnapshotState {
+    index: Index,
+    seq: u64,
+    // keys are expired as of the time the snapshot was taken
+    now: u64,
+    // the pinned files are never closed by compaction, so the reader has handles of its own
+    reader: KvStoreReader,
+    _pin: Pin,
+}
+
+impl Snapshot {
+    // should be called with the index locked, so no compaction is swapped in meanwhile.
+    pub(super) fn new(index: Index, seq: u64, reader: &KvStoreReader, pins: &Arc<Pins>) -> Self {
+        let state = Arc::new(SnapshotState {
+            index,
+            seq,
+            now: now_ms(),
+            reader: reader.pinned(),
+            _pin: pins.pin(),
+        });
+        Self { state }
+    }
+
+    /// the sequence number of the last write the snapshot sees.
+    /// writes are numbered from 1 since the store is opened, 0 means no write at all.
+    pub fn seq(&self) -> u64 {
+        self.state.seq
+    }
+
+    /// get the value of a key as of the snapshot.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.state.get(key)
+    }
+
+    /// iterate over the keys in `range` and their values, as of the snapshot.
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvPairs> {
+        let keys = self
+            .state
+            .index
+            .range(
+                range.start_bound().map(Vec::as_slice),
+                range.end_bound().map(Vec::as_slice),
+            )
+            .map(|(key, _)| key.clone())
+            .collect();
+        Ok(self.pairs(keys))
+    }
+
+    /// iterate over the keys starting with `prefix` and their values, as of the snapshot.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<KvPairs> {
+        let keys = self
+            .state
+            .index
+            .range(Bound::Included(prefix), Bound::Unbounded)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        Ok(self.pairs(keys))
+    }
+
+    // read the values of `keys` lazily.
+    fn pairs(&self, keys: Vec<Key>) -> KvPairs {
+        let state = self.state.clone();
+        Box::new(keys.into_iter().filter_map(move |key| {
+            state
+                .get(&key)
+                .transpose()
+                .map(|value| value.map(|value| (key, value)))
+        }))
+    }
+}
+
+impl SnapshotState {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key) {
+            Some(meta) if !meta.is_expired(self.now) => self.reader.read_value(key, meta),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// the files compaction and blob GC made obsolete, kept on disk for the snapshots still reading them.
+pub(super) struct Pins {
+    state: Mutex<PinState>,
+}
+
+#[derive(Default)]
+struct PinState {
+    // bumped every time obsolete files are retired
+    epoch: u64,
+    // number of live snapshots taken at each epoch
+    live: BTreeMap<u64, usize>,
+    // files retired at each epoch, still pinned by older snapshots
+    obsolete: Vec<(u64, Vec<PathBuf>)>,
+}
+
+impl Pins {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(PinState::default()),
+        }
+    }
+
+    pub fn pin(self: &Arc<Self>) -> Pin {
+        let mut state = self.state.lock().unwrap();
+        let epoch = state.epoch;
+        *state.live.entry(epoch).or_default() += 1;
+        Pin {
+            pins: self.clone(),
+            epoch,
+        }
+    }
+
+    /// remove the files no longer needed, or keep them until no snapshot reads them.
+    /// should be called after the entries moved out of them are swapped into the index.
+    pub fn retire(&self, files: Vec<PathBuf>) -> KvsResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let epoch = state.epoch;
+        state.epoch += 1;
+        if state
+            .live
+            .keys()
+            .next()
+            .is_some_and(|&oldest| oldest <= epoch)
+        {
+            state.obsolete.push((epoch, files));
+            return Ok(());
+        }
+        drop(state);
+        for file in files {
+            remove_if_exists(&file)?;
+        }
+        Ok(())
+    }
+
+    fn unpin(&self, epoch: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(count) = state.live.get_mut(&epoch) {
+            *count -= 1;
+            if *count == 0 {
+                state.live.remove(&epoch);
+            }
+        }
+        let oldest = state.live.keys().next().copied().unwrap_or(u64::MAX);
+        let (unpinned, pinned) = state
+            .obsolete
+            .drain(..)
+            .partition::<Vec<_>, _>(|&(retired, _)| retired < oldest);
+        state.obsolete = pinned;
+        drop(state);
+        for file in unpinned.into_iter().flat_map(|(_, files)| files) {
+            // a file left behind is not in the MANIFEST or not pointed at,
+            // so it is removed on the next `open`
+            remove_if_exists(&file).ok();
+        }
+    }
+}
+
+/// a snapshot's hold on the files of its epoch.
+pub(super) struct Pin {
+    pins: Arc<Pins>,
+    epoch: u64,
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        self.pins.unpin(self.epoch);
+    }
+}
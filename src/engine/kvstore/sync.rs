@@ -0,0 +1,151 @@
+//! make the written logs durable, as the `SyncPolicy` says.
+//!
+//! with `SyncPolicy::Always`, writers append their logs under the writer lock,
+//! then wait outside of it until their write is synced.
+//! the first waiter becomes the leader and syncs the file once for every write appended so far,
+//! so concurrent writers share one `fsync`(group commit).
+//!
+//! the active blob file is synced along with the file being written, before it,
+//! so the values in blob files are durable once the pointers to them are.
+use crate::error::KvsResult;
+use slog::{warn, Logger};
+use std::{
+    fs::File,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+pub(super) struct Syncer {
+    // the file being written
+    file: Mutex<Arc<File>>,
+    // the active blob file, if any
+    blob: Mutex<Option<Arc<File>>>,
+    // number of writes appended so far, which is also the sequence number of the last write
+    written: AtomicU64,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+struct SyncState {
+    // every write up to this sequence number is durable
+    synced: u64,
+    // whether a leader is syncing now
+    syncing: bool,
+}
+
+impl Syncer {
+    pub fn new(file: &File) -> KvsResult<Self> {
+        Ok(Self {
+            file: Mutex::new(Arc::new(file.try_clone()?)),
+            blob: Mutex::new(None),
+            written: AtomicU64::new(0),
+            state: Mutex::new(SyncState {
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        })
+    }
+
+    /// record a write appended to the current file and return its sequence number.
+    /// should be called by the writer, after the write.
+    pub fn appended(&self) -> u64 {
+        self.written.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// sync `blob` along with the file being written from now on, `None` once it is sealed.
+    /// should be called by the writer.
+    pub fn track_blob(&self, blob: Option<&File>) -> KvsResult<()> {
+        *self.blob.lock().unwrap() = blob.map(File::try_clone).transpose()?.map(Arc::new);
+        Ok(())
+    }
+
+    /// make every write to `old` durable and switch to `new`.
+    /// should be called by the writer, so nothing is appended in between.
+    pub fn switch(&self, old: &File, new: &File) -> KvsResult<()> {
+        self.sync_blob()?;
+        old.sync_data()?;
+        *self.file.lock().unwrap() = Arc::new(new.try_clone()?);
+        let written = self.written.load(Ordering::SeqCst);
+        let mut state = self.state.lock().unwrap();
+        state.synced = state.synced.max(written);
+        Ok(())
+    }
+
+    /// wait until the write `seq` is durable, syncing the file if no one else is doing so.
+    pub fn wait_for(&self, seq: u64) -> KvsResult<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= seq {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            state.syncing = true;
+            drop(state);
+            // take the target before the file, so every write up to it is in that file
+            let target = self.written.load(Ordering::SeqCst);
+            let file = self.file.lock().unwrap().clone();
+            let ret = self.sync_blob().and_then(|()| Ok(file.sync_data()?));
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if ret.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            ret?;
+        }
+    }
+
+    // a blob file sealed since it was tracked is synced already.
+    fn sync_blob(&self) -> KvsResult<()> {
+        let blob = self.blob.lock().unwrap().clone();
+        if let Some(blob) = blob {
+            blob.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// make every write so far durable.
+    pub fn sync(&self) -> KvsResult<()> {
+        self.wait_for(self.written.load(Ordering::SeqCst))
+    }
+}
+
+/// the background thread syncing every write periodically, for `SyncPolicy::Interval`.
+pub(super) struct IntervalSyncer {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl IntervalSyncer {
+    pub fn spawn(syncer: Arc<Syncer>, interval: Duration, logger: Logger) -> Self {
+        let (stop, rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            // the sender is only dropped on shutdown
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                if let Err(e) = syncer.sync() {
+                    warn!(logger, "periodic sync failed: {e}");
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
@@ -1,35 +1,366 @@
-use super::Result;
+use super::{
+    expires_at, now_ms, BatchOp, CompareAndSwapError, EngineStats, Keys, KvPairs, Result,
+    WriteBatch,
+};
 use crate::{error::KvsError, KvsEngine};
-use sled::Db;
-use std::path::Path;
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree,
+    },
+    Db, IVec, Transactional, Tree,
+};
+use std::{
+    fs,
+    ops::RangeBounds,
+    path::Path,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// the tree holding the expiry time of the keys set with a TTL,
+/// in milliseconds since the UNIX epoch(big-endian).
+const TTL_TREE: &str = "__kvs_ttl";
+
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+    ttl: Tree,
+    counts: Arc<Counts>,
+}
+
+/// the keys in the data tree and their bytes, counted once on open and kept up to date by the writes,
+/// so `stats` does not walk the db.
+/// an expired key is counted until it is read or `stats` removes it.
+#[derive(Default)]
+struct Counts {
+    keys: AtomicI64,
+    bytes: AtomicI64,
+}
+
+/// how a write changes `Counts`.
+#[derive(Clone, Copy, Default)]
+struct Delta {
+    keys: i64,
+    bytes: i64,
+}
+
+impl std::ops::AddAssign for Delta {
+    fn add_assign(&mut self, other: Self) {
+        self.keys += other.keys;
+        self.bytes += other.bytes;
+    }
+}
 
-pub struct SledKvsEngine(Db);
 impl SledKvsEngine {
     pub fn open(p: impl AsRef<Path>) -> Result<Self> {
-        Ok(Self(sled::open(p)?))
+        let db = sled::open(p)?;
+        let ttl = db.open_tree(TTL_TREE)?;
+        let counts = Counts::default();
+        for pair in db.iter() {
+            let (key, value) = pair?;
+            counts.keys.fetch_add(1, Ordering::SeqCst);
+            counts
+                .bytes
+                .fetch_add((key.len() + value.len()) as i64, Ordering::SeqCst);
+        }
+        Ok(Self {
+            db,
+            ttl,
+            counts: Arc::new(counts),
+        })
+    }
+
+    // write the value and the expiry time of a key atomically.
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let delta = (&*self.db, &self.ttl)
+            .transaction(|(db, ttl)| {
+                let delta = put(db, &key, Some(&value))?;
+                match expires_at {
+                    Some(expires_at) => ttl.insert(key.as_slice(), &expires_at.to_be_bytes())?,
+                    None => ttl.remove(key.as_slice())?,
+                };
+                Ok(delta)
+            })
+            .map_err(from_tx)?;
+        self.count(delta);
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // only called once the transaction of the write has committed, as it may be retried.
+    fn count(&self, delta: Delta) {
+        self.counts.keys.fetch_add(delta.keys, Ordering::SeqCst);
+        self.counts.bytes.fetch_add(delta.bytes, Ordering::SeqCst);
+    }
+
+    // errors are kept, for the caller of the scan to see them
+    fn is_expired_pair(&self, pair: &Result<(Vec<u8>, Vec<u8>)>) -> bool {
+        match pair {
+            Ok((key, _)) => self.is_expired(key),
+            Err(_) => false,
+        }
+    }
+
+    fn is_expired(&self, key: &[u8]) -> bool {
+        self.ttl
+            .get(key)
+            .is_ok_and(|t| t.is_some_and(|t| expired(&t)))
     }
 }
 impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.0.insert(key, value.into_bytes())?;
-        self.0.flush()?;
+    // sled keeps no version of the values, so the value itself is compared
+    type Version = Option<Vec<u8>>;
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, value, None)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.insert(key, value, Some(expires_at(ttl)))
+    }
+
+    // an expired key is removed when it is read.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (value, delta) = (&*self.db, &self.ttl)
+            .transaction(|(db, ttl)| {
+                if ttl.get(key)?.is_some_and(|t| expired(&t)) {
+                    let delta = put(db, key, None)?;
+                    ttl.remove(key)?;
+                    return Ok((None, delta));
+                }
+                Ok((db.get(key)?.map(|v| v.to_vec()), Delta::default()))
+            })
+            .map_err(from_tx)?;
+        self.count(delta);
+        Ok(value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        let delta = (&*self.db, &self.ttl)
+            .transaction(|(db, ttl)| {
+                let expired = ttl.remove(key)?.is_some_and(|t| expired(&t));
+                match put(db, key, None)? {
+                    delta if delta.keys < 0 && !expired => Ok(delta),
+                    _ => Err(ConflictableTransactionError::Abort(
+                        KvsError::key_not_found(key),
+                    )),
+                }
+            })
+            .map_err(from_tx)?;
+        self.count(delta);
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let (data, ttls) = to_sled_batches(batch);
+        // the two trees are only updated together in a transaction
+        let delta = (&*self.db, &self.ttl)
+            .transaction(|(db, ttl)| {
+                let delta = put_all(db, &data)?;
+                ttl.apply_batch(&ttls)?;
+                Ok(delta)
+            })
+            .map_err(from_tx)?;
+        self.count(delta);
+        self.db.flush()?;
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .0
-            .get(key)?
-            .map(|v| String::from_utf8_lossy(v.as_ref()).to_string()))
+    // `Tree::compare_and_swap` cannot clear the expiry time of the key in the same step,
+    // so the swap is done in a transaction over both trees instead.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        let swapped = (&*self.db, &self.ttl)
+            .transaction(|(db, ttl)| {
+                let current = match ttl.get(&key)? {
+                    Some(t) if expired(&t) => None,
+                    _ => db.get(&key)?.map(|v| v.to_vec()),
+                };
+                if current != expected {
+                    return Ok((Err(CompareAndSwapError { current }), Delta::default()));
+                }
+                let delta = put(db, &key, new.as_deref())?;
+                ttl.remove(key.as_slice())?;
+                Ok((Ok(()), delta))
+            })
+            .map_err(from_tx)?;
+        self.count(swapped.1);
+        self.db.flush()?;
+        Ok(swapped.0)
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let ret = self
-            .0
-            .remove(&key)?
-            .and(Some(()))
-            .ok_or(KvsError::KeyNotFound { key });
-        self.0.flush()?;
-        ret
+    fn get_versioned(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Self::Version)> {
+        let value = self.get(key)?;
+        Ok((value.clone(), value))
     }
+
+    fn commit(&self, reads: &[(Vec<u8>, Self::Version)], writes: WriteBatch) -> Result<bool> {
+        let (data, ttls) = to_sled_batches(writes);
+        let committed = (&*self.db, &self.ttl)
+            .transaction(|(db, ttl)| {
+                for (key, version) in reads {
+                    let current = match ttl.get(key)? {
+                        Some(t) if expired(&t) => None,
+                        _ => db.get(key)?.map(|v| v.to_vec()),
+                    };
+                    if current != *version {
+                        return Ok((false, Delta::default()));
+                    }
+                }
+                let delta = put_all(db, &data)?;
+                ttl.apply_batch(&ttls)?;
+                Ok((true, delta))
+            })
+            .map_err(from_tx)?;
+        self.count(committed.1);
+        self.db.flush()?;
+        Ok(committed.0)
+    }
+
+    fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<KvPairs> {
+        let engine = self.clone();
+        Ok(Box::new(
+            self.db
+                .range(range)
+                .map(to_vecs)
+                .filter(move |pair| !engine.is_expired_pair(pair)),
+        ))
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvPairs> {
+        let engine = self.clone();
+        Ok(Box::new(
+            self.db
+                .scan_prefix(prefix)
+                .map(to_vecs)
+                .filter(move |pair| !engine.is_expired_pair(pair)),
+        ))
+    }
+
+    fn keys(&self) -> Result<Keys> {
+        let engine = self.clone();
+        Ok(Box::new(
+            self.db
+                .iter()
+                .keys()
+                .map(|key| Ok(key?.to_vec()))
+                .filter(move |key| !matches!(key, Ok(key) if engine.is_expired(key))),
+        ))
+    }
+
+    // sled's export walks every tree as writes go on, so the copy is not as of a single write:
+    // a key written meanwhile may be copied with the TTL of another write of it.
+    fn checkpoint(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)?;
+        if fs::read_dir(dest)?.next().is_some() {
+            return Err(KvsError::InvalidOption(format!(
+                "checkpoint dir `{}` is not empty",
+                dest.display()
+            )));
+        }
+        let copy = sled::open(dest)?;
+        copy.import(self.db.export());
+        copy.flush()?;
+        Ok(())
+    }
+
+    // sled does not expose its files or its compaction,
+    // so its whole size on disk is reported as a single segment.
+    // the counts are taken from `Counts` once the expired keys are removed,
+    // which only walks the keys with a TTL.
+    fn stats(&self) -> Result<EngineStats> {
+        for pair in self.ttl.iter() {
+            let (key, ttl) = pair?;
+            if expired(&ttl) {
+                self.get(&key)?;
+            }
+        }
+        let mut stats = EngineStats {
+            keys: self.counts.keys.load(Ordering::SeqCst).max(0) as u64,
+            live_bytes: self.counts.bytes.load(Ordering::SeqCst).max(0) as u64,
+            ..EngineStats::default()
+        };
+        let size = self.db.size_on_disk()?;
+        stats.dead_bytes = size.saturating_sub(stats.live_bytes);
+        stats.segment_sizes = vec![size];
+        Ok(stats)
+    }
+}
+
+// the writes to the data tree and the batch of the writes to the TTL tree.
+// the data tree is written key by key, to count the values it replaces.
+fn to_sled_batches(batch: WriteBatch) -> (Vec<Put>, sled::Batch) {
+    let mut data = Vec::new();
+    let mut ttls = sled::Batch::default();
+    for op in batch {
+        match op {
+            BatchOp::Set { key, value, ttl } => {
+                match ttl {
+                    Some(ttl) => ttls.insert(key.as_slice(), &expires_at(ttl).to_be_bytes()),
+                    None => ttls.remove(key.as_slice()),
+                }
+                data.push((key, Some(value)));
+            }
+            BatchOp::Remove { key } => {
+                ttls.remove(key.as_slice());
+                data.push((key, None));
+            }
+        }
+    }
+    (data, ttls)
+}
+
+// a write of the data tree, `None` to remove the key.
+type Put = (Vec<u8>, Option<Vec<u8>>);
+
+// set `key` to `value`, or remove it if `None`, in the data tree, and return how it changes `Counts`.
+fn put(
+    db: &TransactionalTree,
+    key: &[u8],
+    value: Option<&[u8]>,
+) -> ConflictableTransactionResult<Delta, KvsError> {
+    let old = match value {
+        Some(value) => db.insert(key, value)?,
+        None => db.remove(key)?,
+    };
+    let size = |value: &[u8]| (key.len() + value.len()) as i64;
+    Ok(Delta {
+        keys: value.is_some() as i64 - old.is_some() as i64,
+        bytes: value.map_or(0, size) - old.as_deref().map_or(0, size),
+    })
+}
+
+fn put_all(db: &TransactionalTree, data: &[Put]) -> ConflictableTransactionResult<Delta, KvsError> {
+    let mut delta = Delta::default();
+    for (key, value) in data {
+        delta += put(db, key, value.as_deref())?;
+    }
+    Ok(delta)
+}
+
+fn expired(expires_at: &IVec) -> bool {
+    let expires_at = u64::from_be_bytes(expires_at.as_ref().try_into().unwrap_or([0; 8]));
+    expires_at <= now_ms()
+}
+
+fn from_tx(e: TransactionError<KvsError>) -> KvsError {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
+}
+
+fn to_vecs(pair: sled::Result<(IVec, IVec)>) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (key, value) = pair?;
+    Ok((key.to_vec(), value.to_vec()))
 }
@@ -0,0 +1,83 @@
+//! optimistic transactions over any `KvsEngine`.
+//!
+//! a transaction reads through to the engine, remembering the version of every key it reads,
+//! and buffers its writes. on commit, the engine applies the writes atomically
+//! only if none of the keys read has changed since, otherwise the transaction conflicts.
+use super::{BatchOp, KvsEngine, Result, WriteBatch};
+use crate::error::KvsError;
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    time::Duration,
+};
+
+/// a transaction in progress, see `KvsEngine::transaction`.
+pub struct Transaction<'a, E: KvsEngine> {
+    engine: &'a E,
+    // the version of every key read from the engine
+    reads: BTreeMap<Vec<u8>, E::Version>,
+    // the last write to every key written
+    writes: BTreeMap<Vec<u8>, BatchOp>,
+}
+
+impl<'a, E: KvsEngine> Transaction<'a, E> {
+    pub fn new(engine: &'a E) -> Self {
+        Self {
+            engine,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// get the value of a key, as written by this transaction if it was.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(key) {
+            Some(BatchOp::Set { value, .. }) => return Ok(Some(value.clone())),
+            Some(BatchOp::Remove { .. }) => return Ok(None),
+            None => (),
+        }
+        let (value, version) = self.engine.get_versioned(key)?;
+        // the first read is the one validated on commit
+        if let Entry::Vacant(e) = self.reads.entry(key.to_vec()) {
+            e.insert(version);
+        }
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        let op = BatchOp::Set {
+            key: key.clone(),
+            value,
+            ttl: None,
+        };
+        self.writes.insert(key, op);
+    }
+
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) {
+        let op = BatchOp::Set {
+            key: key.clone(),
+            value,
+            ttl: Some(ttl),
+        };
+        self.writes.insert(key, op);
+    }
+
+    /// return an error if the key does not exist, like `KvsEngine::remove`.
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        if self.get(key)?.is_none() {
+            return Err(KvsError::key_not_found(key));
+        }
+        let op = BatchOp::Remove { key: key.to_vec() };
+        self.writes.insert(key.to_vec(), op);
+        Ok(())
+    }
+
+    /// apply the writes if nothing read has changed, return `Ok(false)` on conflict.
+    pub fn commit(self) -> Result<bool> {
+        let mut batch = WriteBatch::new();
+        for op in self.writes.into_values() {
+            batch.push(op);
+        }
+        let reads = self.reads.into_iter().collect::<Vec<_>>();
+        self.engine.commit(&reads, batch)
+    }
+}
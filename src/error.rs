@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, string::FromUtf8Error};
 
 use crate::server::KvsEngineSel;
 use thiserror::Error;
@@ -9,14 +9,49 @@ pub enum KvsError {
     #[error("kvs-cli: {0}")]
     CommandError(&'static str),
 
+    #[error("kvs-txn: {0}")]
+    TransactionError(&'static str),
+
+    #[error("kvs-backup: {0}")]
+    BackupError(&'static str),
+
     #[error("kvs-compact: {0}")]
     CompactionError(String),
 
+    #[error("kvs-corrupted: record at offset {offset} of file {file_id}: {reason}")]
+    Corrupted {
+        file_id: u32,
+        offset: u64,
+        reason: Corruption,
+    },
+
+    #[error("kvs-corrupted: blob at offset {offset} of blob file {blob_id}: {reason}")]
+    CorruptedBlob {
+        blob_id: u32,
+        offset: u64,
+        reason: Corruption,
+    },
+
+    #[error("kvs-corrupted: MANIFEST: {0}")]
+    CorruptedManifest(Corruption),
+
+    #[error("kvs-crypto: records are encrypted with key {key_id:08x}, which is not given")]
+    UnknownKey { key_id: u32 },
+
+    #[error("kvs-import: line {line}: {source}")]
+    Import {
+        line: u64,
+        source: serde_json::Error,
+    },
+
     #[error("kvs-inner: {0}")]
     Inner(String),
 
     #[error("kvs: invalid engine `{0}`, choose either `kvs` or `sled`")]
     InvalidEngine(String),
+
+    #[error("kvs: {0}")]
+    InvalidOption(String),
     #[error("kvs-io: {source}")]
     IO {
         #[from]
@@ -32,15 +67,60 @@ pub enum KvsError {
     #[error("{key}")]
     KeyNotFound { key: String },
 
+    #[error("kvs: the store is opened read-only")]
+    ReadOnly,
+
     #[error("serde: {source}")]
     Serde {
         #[from]
         source: serde_json::Error,
     },
 
+    #[error("bincode: {source}")]
+    Bincode {
+        #[from]
+        source: bincode::Error,
+    },
+
+    #[error("kvs: value is not valid UTF-8: {source}")]
+    Utf8 {
+        #[from]
+        source: FromUtf8Error,
+    },
+
     #[error("sled: {source}")]
     Sled {
         #[from]
         source: sled::Error,
     },
 }
+
+impl KvsError {
+    /// `KeyNotFound` for a key of arbitrary bytes.
+    pub fn key_not_found(key: &[u8]) -> Self {
+        Self::KeyNotFound {
+            key: String::from_utf8_lossy(key).into_owned(),
+        }
+    }
+}
+
+/// why a record in a kvs log file is considered corrupted.
+#[derive(Debug, Error)]
+pub enum Corruption {
+    #[error("record is truncated")]
+    Truncated,
+    #[error("record length does not match its header")]
+    LengthMismatch,
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+    #[error("unknown record type {0}")]
+    UnknownType(u8),
+    #[error("unexpected record type {0}")]
+    UnexpectedType(u8),
+    #[error("invalid payload: {0}")]
+    InvalidPayload(String),
+    #[error("encrypted with unknown key {0:08x}")]
+    UnknownKey(u32),
+    #[error("encrypted payload fails authentication")]
+    AuthenticationFailed,
+}
@@ -5,5 +5,5 @@ pub mod error;
 pub mod server;
 
 pub use engine::kvstore::KvStore;
-pub use engine::KvsEngine;
 pub use engine::Result;
+pub use engine::{CompareAndSwapError, EngineStats, Keys, KvPairs, KvsEngine, WriteBatch};
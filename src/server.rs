@@ -1,71 +1,194 @@
 use crate::{
     cli::{Request, Response},
+    engine::Transaction,
     error::KvsError,
     KvsEngine, Result,
 };
 use slog::{warn, Logger};
 use std::{
     fmt::Display,
+    fs,
     io::{self, Write},
     net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::{Component, Path, PathBuf},
+    thread,
 };
 
-pub struct KvsServer<'log> {
-    engine: Box<dyn KvsEngine>,
+/// the file in the dir of a db telling which engine it is of.
+pub const ENGINE_MARKER: &str = "00engine";
+
+pub struct KvsServer<'log, E: KvsEngine> {
+    engine: E,
     logger: &'log Logger,
+    backups: Backups,
+}
+
+// where the backups asked by clients are written, and what they are marked with
+#[derive(Clone, Default)]
+struct Backups {
+    dir: Option<PathBuf>,
+    engine_sel: Option<KvsEngineSel>,
 }
-impl<'log> KvsServer<'log> {
-    pub fn new(engine: impl KvsEngine + 'static, logger: &'log Logger) -> Self {
+
+impl<'log, E: KvsEngine> KvsServer<'log, E> {
+    pub fn new(engine: E, logger: &'log Logger) -> Self {
         Self {
-            engine: Box::new(engine),
+            engine,
             logger,
+            backups: Backups::default(),
         }
     }
-    pub fn run(mut self, socket: impl ToSocketAddrs) -> Result<()> {
+    /// write the `ENGINE_MARKER` of `engine_sel` into every backup,
+    /// so `kvs-server` opens it with the same engine.
+    pub fn engine_sel(mut self, engine_sel: KvsEngineSel) -> Self {
+        self.backups.engine_sel = Some(engine_sel);
+        self
+    }
+    /// write backups into dirs under `dir`, which clients cannot name a path out of.
+    /// backups are refused without it.
+    pub fn backup_dir(mut self, dir: PathBuf) -> Self {
+        self.backups.dir = Some(dir);
+        self
+    }
+    // every connection is served on its own thread with its own clone of the engine
+    pub fn run(self, socket: impl ToSocketAddrs) -> Result<()> {
         let listener = TcpListener::bind(socket)?;
         for stream in listener.incoming() {
-            if let Err(e) = stream
-                .map_err(|e| e.into())
-                .and_then(|stream| self.serve(stream))
-            {
-                warn!(self.logger, "{e}");
+            match stream {
+                Ok(stream) => {
+                    let engine = self.engine.clone();
+                    let backups = self.backups.clone();
+                    let logger = self.logger.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(&engine, &backups, stream) {
+                            warn!(logger, "{e}");
+                        }
+                    });
+                }
+                Err(e) => warn!(self.logger, "{e}"),
             }
         }
         unreachable!()
     }
+}
+
+// we can use `?` to throw errors, which will be handled by the connection thread
+fn serve<E: KvsEngine>(engine: &E, backups: &Backups, stream: TcpStream) -> Result<()> {
+    let mut reader = io::BufReader::new(&stream);
+    let mut writer = io::BufWriter::new(&stream);
+    // the transaction begun on this connection
+    let mut txn = None;
+    loop {
+        let req = match bincode::deserialize_from::<_, Request>(&mut reader) {
+            Ok(req) => req,
+            Err(e) => match *e {
+                // the client has closed the connection, which aborts the transaction
+                bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(())
+                }
+                e => return Err(Box::new(e).into()),
+            },
+        };
+        let response = match (req, &mut txn) {
+            (Request::Begin, Some(_)) => {
+                Response::Begin(txn_error("a transaction is already begun"))
+            }
+            (Request::Begin, None) => {
+                txn = Some(Transaction::new(engine));
+                Response::Begin(Ok(()))
+            }
+            (Request::Commit, Some(_)) => Response::Commit(t(txn.take().unwrap().commit())),
+            (Request::Commit, None) => Response::Commit(txn_error("no transaction begun")),
+            (Request::Abort, Some(_)) => {
+                txn = None;
+                Response::Abort(Ok(()))
+            }
+            (Request::Abort, None) => Response::Abort(txn_error("no transaction begun")),
+            (req, Some(txn)) => handle_in_txn(txn, req),
+            (req, None) => handle(engine, backups, req),
+        };
+        bincode::serialize_into(&mut writer, &response)?;
+        writer.flush()?
+    }
+}
+
+#[inline]
+fn t<T, E: Display>(result: std::result::Result<T, E>) -> std::result::Result<T, String> {
+    result.map_err(|e| e.to_string())
+}
+
+fn txn_error<T>(reason: &'static str) -> std::result::Result<T, String> {
+    Err(KvsError::TransactionError(reason).to_string())
+}
+
+fn handle(engine: &impl KvsEngine, backups: &Backups, req: Request) -> Response {
+    match req {
+        Request::Set { key, value, ttl } => Response::Set(t(match ttl {
+            Some(ttl) => engine.set_with_ttl(key, value, ttl),
+            None => engine.set(key, value),
+        })),
+        Request::Get { key } => Response::Get(t(engine.get(&key))),
+        Request::Remove { key } => Response::Remove(t(engine.remove(&key))),
+        Request::Batch(batch) => Response::Batch(t(engine.write_batch(batch))),
+        Request::CompareAndSwap { key, expected, new } => {
+            Response::CompareAndSwap(t(engine.compare_and_swap(key, expected, new)))
+        }
+        Request::Stats => Response::Stats(t(engine.stats())),
+        Request::Backup { dest } => Response::Backup(t(backup(engine, backups, &dest))),
+        Request::Begin | Request::Commit | Request::Abort => {
+            unreachable!("transaction requests are handled by `serve`")
+        }
+    }
+}
 
-    // we can use `?` to throw errors, which will be handled by upper function `run`
-    fn serve(&mut self, stream: TcpStream) -> Result<()> {
-        let reader = io::BufReader::new(&stream);
-        let mut writer = io::BufWriter::new(&stream);
-        let req_reader = serde_json::Deserializer::from_reader(reader).into_iter::<Request>();
-        #[inline]
-        fn t<T, E: Display>(result: std::result::Result<T, E>) -> std::result::Result<T, String> {
-            result.map_err(|e| e.to_string())
+fn handle_in_txn<E: KvsEngine>(txn: &mut Transaction<'_, E>, req: Request) -> Response {
+    match req {
+        Request::Set { key, value, ttl } => {
+            match ttl {
+                Some(ttl) => txn.set_with_ttl(key, value, ttl),
+                None => txn.set(key, value),
+            }
+            Response::Set(Ok(()))
+        }
+        Request::Get { key } => Response::Get(t(txn.get(&key))),
+        Request::Remove { key } => Response::Remove(t(txn.remove(&key))),
+        Request::Batch(_) => Response::Batch(txn_error("not supported in a transaction")),
+        Request::CompareAndSwap { .. } => {
+            Response::CompareAndSwap(txn_error("not supported in a transaction"))
         }
-        for req in req_reader {
-            let response = match req? {
-                Request::Set { key, value } => Response::Set(t(self.engine.set(key, value))),
-                Request::Get { key } => Response::Get(t(self.engine.get(key))),
-                Request::Remove { key } => Response::Remove(t(self.engine.remove(key))),
-            };
-            serde_json::to_writer(&mut writer, &response)?;
-            writer.flush()?
+        Request::Stats => Response::Stats(txn_error("not supported in a transaction")),
+        Request::Backup { .. } => Response::Backup(txn_error("not supported in a transaction")),
+        Request::Begin | Request::Commit | Request::Abort => {
+            unreachable!("transaction requests are handled by `serve`")
         }
-        Ok(())
     }
 }
 
-#[derive(Debug, PartialEq)]
+fn backup(engine: &impl KvsEngine, backups: &Backups, dest: &Path) -> Result<()> {
+    let dir = backups.dir.as_ref().ok_or(KvsError::BackupError(
+        "backups are disabled, start the server with a backup dir",
+    ))?;
+    // `dest` comes from any client, so it must not name a path out of the backup dir
+    if dest.as_os_str().is_empty() || !dest.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(KvsError::BackupError(
+            "the dest of a backup must be a relative path without `..`",
+        ));
+    }
+    let dest = dir.join(dest);
+    engine.checkpoint(&dest)?;
+    if let Some(engine_sel) = backups.engine_sel {
+        fs::write(dest.join(ENGINE_MARKER), engine_sel.to_string())?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub enum KvsEngineSel {
+    #[default]
     KvStore,
     SledKvsEngine,
 }
-impl Default for KvsEngineSel {
-    fn default() -> Self {
-        Self::KvStore
-    }
-}
 impl std::fmt::Display for KvsEngineSel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let display = match self {
@@ -1,4 +1,18 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    engine::{
+        jsonl::{self, ImportOptions, ImportReport},
+        kvstore::{Compression, EncryptionKey, KvStoreOptions, SyncPolicy},
+        sled::SledKvsEngine,
+    },
+    error::KvsError,
+    CompareAndSwapError, KvStore, KvsEngine, Result, WriteBatch,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,19 +20,19 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    store.set_str("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_str("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_str("key2")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_str("key2")?, Some("value2".to_owned()));
 
     Ok(())
 }
@@ -27,19 +41,19 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_str("key1")?, Some("value1".to_owned()));
+    store.set_str("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get_str("key1")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key1")?, Some("value2".to_owned()));
+    store.set_str("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get_str("key1")?, Some("value3".to_owned()));
 
     Ok(())
 }
@@ -48,15 +62,15 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_str("key2")?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key2")?, None);
 
     Ok(())
 }
@@ -64,18 +78,18 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.remove_str("key1").is_err());
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove_str("key1").is_ok());
+    assert_eq!(store.get_str("key1")?, None);
     Ok(())
 }
 
@@ -84,7 +98,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -102,7 +116,7 @@ fn compaction() -> Result<()> {
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             let value = format!("{}", iter);
-            store.set(key, value)?;
+            store.set_str(key, value)?;
         }
 
         let new_size = dir_size();
@@ -114,13 +128,1797 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get_str(key)?, Some(format!("{}", iter)));
         }
         return Ok(());
     }
 
     panic!("No compaction detected");
 }
+
+// Clones of a store should share data and work from multiple threads.
+#[test]
+fn concurrent_set_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    store.set_str(format!("key{t}_{i}"), format!("value{i}"))?;
+                    assert_eq!(
+                        store.get_str(format!("key{t}_{i}"))?,
+                        Some(format!("value{i}"))
+                    );
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get_str(format!("key{t}_{i}"))?,
+                Some(format!("value{i}"))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Return the paths of the non-empty kvs files, in ascending order of file id.
+fn kvs_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension() == Some("kvs".as_ref()) && fs::metadata(p).unwrap().len() > 0)
+        .collect::<Vec<_>>();
+    files.sort_by_key(|p| {
+        p.file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u32>().ok())
+    });
+    files
+}
+
+// Corrupt the newest non-empty kvs file on disk.
+fn flip_byte_in_kvs_file(dir: &Path, pos_from_end: usize) {
+    let path = kvs_files(dir).pop().expect("no kvs file found");
+    let mut buf = fs::read(&path).unwrap();
+    let pos = buf.len() - pos_from_end;
+    buf[pos] ^= 0xff;
+    fs::write(&path, buf).unwrap();
+}
+
+fn hint_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension() == Some("hint".as_ref()))
+        .collect()
+}
+
+fn remove_hint_files(dir: &Path) {
+    for path in hint_files(dir) {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+// A flipped bit should be reported as a corrupted record when opening.
+#[test]
+fn detect_corruption_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    store.set_str("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // hint files would let `open` skip reading the records
+    remove_hint_files(temp_dir.path());
+    flip_byte_in_kvs_file(temp_dir.path(), 3);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corrupted { .. })
+    ));
+    Ok(())
+}
+
+// A flipped bit should be reported as a corrupted record when reading the value.
+#[test]
+fn detect_corruption_on_read() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+
+    flip_byte_in_kvs_file(temp_dir.path(), 3);
+    assert!(matches!(
+        store.get_str("key1"),
+        Err(KvsError::Corrupted { .. })
+    ));
+    Ok(())
+}
+
+// An incomplete record at the tail of the newest file should be discarded on open.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // simulate a crash in the middle of writing a record
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key2".to_owned(), "value2".to_owned())?;
+    std::mem::forget(store);
+    let path = kvs_files(temp_dir.path()).pop().unwrap();
+    let buf = fs::read(&path)?;
+    fs::write(&path, &buf[..buf.len() - 3])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_str("key2")?, None);
+    store.set_str("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_str("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A torn first record should be discarded, while a newest file which is not a torn record
+// should fail the open and be left as it is.
+#[test]
+fn torn_tail_at_start_of_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    std::mem::forget(store);
+    let path = kvs_files(temp_dir.path()).pop().unwrap();
+    let buf = fs::read(&path)?;
+    fs::write(&path, &buf[..buf.len() - 3])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key1")?, None);
+    store.set_str("key2".to_owned(), "value2".to_owned())?;
+    std::mem::forget(store);
+    let path = kvs_files(temp_dir.path()).pop().unwrap();
+    fs::write(&path, "this is not a kvs file")?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corrupted { offset: 0, .. })
+    ));
+    assert_eq!(fs::read_to_string(&path)?, "this is not a kvs file");
+    Ok(())
+}
+
+// An incomplete record in an older file is not a torn write and should fail the open.
+#[test]
+fn torn_record_in_older_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let path = kvs_files(temp_dir.path()).pop().unwrap();
+    let buf = fs::read(&path)?;
+    fs::write(temp_dir.path().join("100.kvs"), &buf)?;
+    fs::write(&path, &buf[..buf.len() - 3])?;
+    // without a MANIFEST, every kvs file is live
+    fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corrupted { .. })
+    ));
+    Ok(())
+}
+
+// A store written before the records were framed should be upgraded on open, not lost.
+#[test]
+fn open_unframed_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logs = [
+        r#"{"key":"a","value":"1"}"#,
+        r#"{"key":"b","value":"2"}"#,
+        r#"{"key":"c","value":"3"}"#,
+        r#"{"key":"a","value":null}"#,
+        r#"{"key":"b","value":"4"}"#,
+    ];
+    fs::write(temp_dir.path().join("1.kvs"), logs.concat())?;
+
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true)),
+        Err(KvsError::InvalidOption(_))
+    ));
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("1.kvs"))?,
+        logs.concat()
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("a")?, None);
+    assert_eq!(store.get_str("b")?, Some("4".to_owned()));
+    assert_eq!(store.get_str("c")?, Some("3".to_owned()));
+    store.set_str("d", "5")?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("a")?, None);
+    assert_eq!(store.get_str("b")?, Some("4".to_owned()));
+    assert_eq!(store.get_str("c")?, Some("3".to_owned()));
+    assert_eq!(store.get_str("d")?, Some("5".to_owned()));
+    Ok(())
+}
+
+// Compaction should leave hint files, and a broken hint file should fall back to replaying.
+#[test]
+fn open_with_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set_str(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    store.remove_str("key0")?;
+    drop(store);
+    assert_eq!(hint_files(temp_dir.path()).len(), 1);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key0")?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get_str(format!("key{key_id}"))?,
+            Some(format!("value{key_id}"))
+        );
+    }
+    drop(store);
+
+    for path in hint_files(temp_dir.path()) {
+        fs::write(path, b"garbage")?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key0")?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get_str(format!("key{key_id}"))?,
+            Some(format!("value{key_id}"))
+        );
+    }
+    Ok(())
+}
+
+// Compaction runs in the background, so reads and writes should stay correct while it runs.
+#[test]
+fn compaction_with_concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..300 {
+                    for key_id in 0..100 {
+                        let key = format!("key{t}_{key_id}");
+                        store.set_str(key.clone(), format!("{iter}"))?;
+                        assert_eq!(store.get_str(key)?, Some(format!("{iter}")));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..4 {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get_str(format!("key{t}_{key_id}"))?,
+                Some("299".to_owned())
+            );
+        }
+    }
+    Ok(())
+}
+
+// A read-only store should serve reads, refuse writes and leave the files untouched.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let files = kvs_files(temp_dir.path());
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert_eq!(store.get_str("key1")?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.set_str("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.remove_str("key1"), Err(KvsError::ReadOnly)));
+    drop(store);
+    assert_eq!(kvs_files(temp_dir.path()), files);
+    Ok(())
+}
+
+// A lower compaction threshold should trigger compaction earlier.
+#[test]
+fn compaction_threshold_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set_str("key1".to_owned(), format!("{iter}"))?;
+    }
+    // compaction runs in the background
+    for _ in 0..100 {
+        if !hint_files(temp_dir.path()).is_empty() {
+            assert_eq!(store.get_str("key1")?, Some("99".to_owned()));
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("No compaction detected");
+}
+
+// The files of a failed compaction should be compacted again,
+// so a key removed after it stays removed once the tombstone is compacted away.
+#[test]
+fn retry_failed_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    // the first compaction writes into 2.kvs, which cannot be created as a file
+    fs::create_dir(temp_dir.path().join("2.kvs"))?;
+    for key_id in 0..10 {
+        store.set_str(format!("key{key_id}"), "value")?;
+    }
+    store.set_str("key0", "value0")?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.stats()?.compactions, 0);
+
+    fs::remove_dir(temp_dir.path().join("2.kvs"))?;
+    store.remove_str("key1")?;
+    for _ in 0..100 {
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.stats()?.compactions, 1);
+    assert!(!temp_dir.path().join("1.kvs").exists());
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get_str("key0")?, Some("value0".to_owned()));
+    assert_eq!(store.get_str("key1")?, None);
+    for key_id in 2..10 {
+        assert_eq!(
+            store.get_str(format!("key{key_id}"))?,
+            Some("value".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// A kvs file not listed in the MANIFEST, like a half-written compacted file, should be ignored.
+#[test]
+fn ignore_file_not_in_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let stale = fs::read(kvs_files(temp_dir.path()).pop().unwrap())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // a newer file with a stale value, which would win on replay
+    let leftover = temp_dir.path().join("100.kvs");
+    fs::write(&leftover, stale)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key1")?, Some("value2".to_owned()));
+    assert!(!leftover.exists());
+    Ok(())
+}
+
+// Every sync policy should keep concurrent writes correct.
+#[test]
+fn sync_policies() -> Result<()> {
+    for policy in [
+        SyncPolicy::Always,
+        SyncPolicy::Interval(10),
+        SyncPolicy::Never,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().sync_policy(policy))?;
+        let handles = (0..4)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for i in 0..50 {
+                        store.set_str(format!("key{t}_{i}"), format!("value{i}"))?;
+                    }
+                    store.remove_str(format!("key{t}_0"))
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        for t in 0..4 {
+            assert_eq!(store.get_str(format!("key{t}_0"))?, None);
+            for i in 1..50 {
+                assert_eq!(
+                    store.get_str(format!("key{t}_{i}"))?,
+                    Some(format!("value{i}"))
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_sync_policy() {
+    assert_eq!("always".parse::<SyncPolicy>().unwrap(), SyncPolicy::Always);
+    assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+    assert_eq!(
+        "interval:100".parse::<SyncPolicy>().unwrap(),
+        SyncPolicy::Interval(100)
+    );
+    assert!("interval:0".parse::<SyncPolicy>().is_err());
+    assert!("interval".parse::<SyncPolicy>().is_err());
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+}
+
+// The file being written should be sealed once it reaches the max segment size.
+#[test]
+fn roll_at_max_segment_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set_str(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    assert!(kvs_files(temp_dir.path()).len() > 1);
+    for path in kvs_files(temp_dir.path()) {
+        // a sealed file may exceed the limit by its last record
+        assert!(fs::metadata(path)?.len() < 1024 + 64);
+    }
+
+    std::mem::forget(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get_str(format!("key{key_id}"))?,
+            Some(format!("value{key_id}"))
+        );
+    }
+    Ok(())
+}
+
+// reads share a single store across threads,
+// from the sealed files and from the file being written while it grows
+#[test]
+fn read_sealed_and_active_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set_str(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    assert!(kvs_files(temp_dir.path()).len() > 1);
+    thread::scope(|s| -> Result<()> {
+        let store = &store;
+        let writer = s.spawn(move || -> Result<()> {
+            for key_id in 100..200 {
+                store.set_str(format!("key{key_id}"), format!("value{key_id}"))?;
+            }
+            Ok(())
+        });
+        let readers = (0..4)
+            .map(|_| {
+                s.spawn(move || -> Result<()> {
+                    for key_id in 0..200 {
+                        match store.get_str(format!("key{key_id}"))? {
+                            Some(value) => assert_eq!(value, format!("value{key_id}")),
+                            None => assert!(key_id >= 100),
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        writer.join().unwrap()?;
+        readers
+            .into_iter()
+            .try_for_each(|reader| reader.join().unwrap())
+    })?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get_str(format!("key{key_id}"))?,
+            Some(format!("value{key_id}"))
+        );
+    }
+    Ok(())
+}
+
+fn check_scan(engine: impl KvsEngine) -> Result<()> {
+    for key in ["a", "ab", "abc", "b", "ba", "c"] {
+        engine.set_str(key.to_owned(), format!("value_{key}"))?;
+    }
+    engine.remove_str("ab")?;
+    let keys = |pairs: kvs::KvPairs| -> Result<Vec<String>> {
+        pairs
+            .map(|pair| {
+                let (key, value) = pair?;
+                let key = String::from_utf8(key)?;
+                assert_eq!(value, format!("value_{key}").into_bytes());
+                Ok(key)
+            })
+            .collect()
+    };
+
+    assert_eq!(
+        keys(engine.scan(b"ab".to_vec()..b"ba".to_vec())?)?,
+        ["abc", "b"]
+    );
+    assert_eq!(keys(engine.scan(..)?)?, ["a", "abc", "b", "ba", "c"]);
+    assert_eq!(keys(engine.scan(b"b".to_vec()..)?)?, ["b", "ba", "c"]);
+    assert_eq!(keys(engine.scan_prefix(b"a")?)?, ["a", "abc"]);
+    assert_eq!(keys(engine.scan_prefix(b"b")?)?, ["b", "ba"]);
+    assert!(keys(engine.scan_prefix(b"d")?)?.is_empty());
+    Ok(())
+}
+
+// Scans should return the live keys in the range in order.
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn check_binary(engine: &impl KvsEngine) -> Result<()> {
+    let pairs: [(&[u8], &[u8]); 3] = [
+        (b"\0\xff", b"\xc3\x28"),
+        (b"\"quoted\"\n", b"\0\xfe\0"),
+        (b"\xff", b""),
+    ];
+    for (key, value) in pairs {
+        assert_eq!(engine.get(key)?, Some(value.to_vec()));
+    }
+    let scanned = engine.scan(..)?.collect::<Result<Vec<_>>>()?;
+    let mut expected = pairs.map(|(k, v)| (k.to_vec(), v.to_vec()));
+    expected.sort();
+    assert_eq!(scanned, expected);
+    assert!(matches!(
+        engine.get_str("\"quoted\"\n"),
+        Err(KvsError::Utf8 { .. })
+    ));
+    Ok(())
+}
+
+// Keys and values are arbitrary bytes, kept as they are across reopening.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"\0\xff".to_vec(), b"\xc3\x28".to_vec())?;
+    store.set(b"\"quoted\"\n".to_vec(), b"\0\xfe\0".to_vec())?;
+    store.set(b"\xff".to_vec(), b"".to_vec())?;
+    check_binary(&store)?;
+    drop(store);
+    check_binary(&KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set(b"\0\xff".to_vec(), b"\xc3\x28".to_vec())?;
+    engine.set(b"\"quoted\"\n".to_vec(), b"\0\xfe\0".to_vec())?;
+    engine.set(b"\xff".to_vec(), b"".to_vec())?;
+    check_binary(&engine)
+}
+
+fn check_ttl(engine: impl KvsEngine) -> Result<()> {
+    engine.set_with_ttl(
+        b"key1".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl(
+        b"key3".to_vec(),
+        b"value3".to_vec(),
+        Duration::from_secs(3600),
+    )?;
+    // setting a key again without a TTL makes it permanent
+    engine.set_str("key2", "value2")?;
+    assert_eq!(engine.get_str("key1")?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get_str("key1")?, None);
+    assert!(matches!(
+        engine.remove_str("key1"),
+        Err(KvsError::KeyNotFound { .. })
+    ));
+    let keys = engine
+        .scan(..)?
+        .map(|pair| Ok(pair?.0))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, [b"key2".to_vec(), b"key3".to_vec()]);
+    Ok(())
+}
+
+// Keys set with a TTL should disappear once it has passed.
+#[test]
+fn ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(KvStore::open(temp_dir.path())?)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key1")?, None);
+    assert_eq!(store.get_str("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get_str("key3")?, Some("value3".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Expired keys should be swept in the background and dropped by compaction.
+#[test]
+fn expired_keys_are_compacted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .sweep_interval(Duration::from_millis(10));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set_with_ttl(
+            format!("key{i}").into_bytes(),
+            vec![0; 100],
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set_str("key", "value")?;
+    let dir_size = || -> u64 {
+        kvs_files(temp_dir.path())
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    let full_size = dir_size();
+
+    thread::sleep(Duration::from_millis(200));
+    // the swept logs are garbage, so the next write triggers compaction
+    store.set_str("key", "value")?;
+    for _ in 0..100 {
+        if dir_size() < full_size / 10 {
+            drop(store);
+            let store = KvStore::open(temp_dir.path())?;
+            assert_eq!(store.get_str("key")?, Some("value".to_owned()));
+            assert_eq!(store.scan(..)?.count(), 1);
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("No compaction detected");
+}
+
+// The sweeper should only take out the keys whose TTL is due, not the ones set again since.
+#[test]
+fn sweep_due_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(usize::MAX)
+        .sweep_interval(Duration::from_millis(10));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let ttl = Duration::from_millis(50);
+    for key in ["a", "b", "c", "d"] {
+        store.set_with_ttl(key.as_bytes().to_vec(), b"old".to_vec(), ttl)?;
+    }
+    store.set_str("b", "new")?;
+    store.set_with_ttl(b"c".to_vec(), b"new".to_vec(), Duration::from_secs(3600))?;
+    store.remove_str("d")?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get_str("a")?, None);
+    assert_eq!(store.get_str("b")?, Some("new".to_owned()));
+    assert_eq!(store.get_str("c")?, Some("new".to_owned()));
+    assert_eq!(store.keys()?.collect::<Result<Vec<_>>>()?, [b"b", b"c"]);
+
+    // the keys loaded on open are swept as well
+    store.set_with_ttl(b"e".to_vec(), b"old".to_vec(), ttl)?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.keys()?.collect::<Result<Vec<_>>>()?, [b"b", b"c"]);
+    Ok(())
+}
+
+fn check_write_batch(engine: impl KvsEngine) -> Result<()> {
+    engine.set_str("key1", "value1")?;
+    engine.set_str("key2", "value2")?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key1".to_vec(), b"value3".to_vec())
+        .remove(b"key2".to_vec())
+        .remove(b"missing".to_vec())
+        .set(b"key3".to_vec(), b"value4".to_vec())
+        .remove(b"key3".to_vec())
+        .set(b"key4".to_vec(), b"value5".to_vec());
+    engine.write_batch(batch)?;
+    engine.write_batch(WriteBatch::new())?;
+
+    assert_eq!(engine.get_str("key1")?, Some("value3".to_owned()));
+    assert_eq!(engine.get_str("key2")?, None);
+    assert_eq!(engine.get_str("key3")?, None);
+    assert_eq!(engine.get_str("key4")?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// The writes of a batch should be applied in order.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(KvStore::open(temp_dir.path())?)?;
+    // replayed from the batch record
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get_str("key2")?, None);
+    assert_eq!(store.get_str("key3")?, None);
+    assert_eq!(store.get_str("key4")?, Some("value5".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A batch torn by a crash should be discarded as a whole.
+#[test]
+fn discard_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1", "value1")?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key1".to_vec(), b"value2".to_vec())
+        .set(b"key2".to_vec(), b"value2".to_vec());
+    store.write_batch(batch)?;
+    std::mem::forget(store);
+    // only the last log of the batch is torn
+    let path = kvs_files(temp_dir.path()).pop().unwrap();
+    let buf = fs::read(&path)?;
+    fs::write(&path, &buf[..buf.len() - 3])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_str("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_str("key2")?, None);
+    Ok(())
+}
+
+fn check_compare_and_swap(engine: impl KvsEngine) -> Result<()> {
+    let value = |v: &str| Some(v.as_bytes().to_vec());
+    assert!(engine.set_if_absent(b"key1".to_vec(), b"value1".to_vec())?);
+    assert!(!engine.set_if_absent(b"key1".to_vec(), b"value2".to_vec())?);
+    assert_eq!(
+        engine.compare_and_swap(b"key1".to_vec(), value("value2"), value("value3"))?,
+        Err(CompareAndSwapError {
+            current: value("value1")
+        })
+    );
+    assert_eq!(
+        engine.compare_and_swap(b"key1".to_vec(), value("value1"), value("value3"))?,
+        Ok(())
+    );
+    assert_eq!(engine.get_str("key1")?, Some("value3".to_owned()));
+    // `None` as the new value removes the key
+    assert_eq!(
+        engine.compare_and_swap(b"key1".to_vec(), value("value3"), None)?,
+        Ok(())
+    );
+    assert_eq!(engine.get_str("key1")?, None);
+    assert_eq!(
+        engine.compare_and_swap(b"key1".to_vec(), value("value3"), None)?,
+        Err(CompareAndSwapError { current: None })
+    );
+
+    // concurrent read-modify-write loses no update
+    engine.set_str("counter", "0")?;
+    let handles = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    let mut current = engine.get(b"counter")?;
+                    loop {
+                        let n = String::from_utf8(current.clone().unwrap())?
+                            .parse::<u32>()
+                            .unwrap();
+                        let new = Some((n + 1).to_string().into_bytes());
+                        match engine.compare_and_swap(b"counter".to_vec(), current, new)? {
+                            Ok(()) => break,
+                            Err(e) => current = e.current,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get_str("counter")?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Compare-and-swap should only write when the key holds the expected value.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A snapshot should keep seeing the values as of when it was taken.
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key1", "value1")?;
+    store.set_str("key2", "value2")?;
+    let snapshot = store.snapshot();
+    assert_eq!(snapshot.seq(), 2);
+
+    store.set_str("key1", "value3")?;
+    store.remove_str("key2")?;
+    store.set_str("key3", "value4")?;
+    assert_eq!(store.snapshot().seq(), 5);
+    assert_eq!(snapshot.get(b"key1")?, Some(b"value1".to_vec()));
+    assert_eq!(snapshot.get(b"key2")?, Some(b"value2".to_vec()));
+    assert_eq!(snapshot.get(b"key3")?, None);
+    let pairs = snapshot.scan(..)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        [
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec())
+        ]
+    );
+    Ok(())
+}
+
+// A snapshot of many keys should be isolated from writes all over the key space,
+// and scans should see every key in order.
+#[test]
+fn snapshot_of_many_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compaction_threshold(usize::MAX),
+    )?;
+    let key = |key_id: usize| format!("key{key_id:05}");
+    for key_id in (0..10000).rev() {
+        store.set_str(key(key_id), "old")?;
+    }
+    let snapshot = store.snapshot();
+    for key_id in (0..10000).step_by(3) {
+        store.set_str(key(key_id), "new")?;
+    }
+    for key_id in (1..10000).step_by(3) {
+        store.remove_str(key(key_id))?;
+    }
+    store.set_str("", "empty")?;
+
+    let pairs = snapshot.scan(..)?.collect::<Result<Vec<_>>>()?;
+    let expected = (0..10000)
+        .map(|key_id| (key(key_id).into_bytes(), b"old".to_vec()))
+        .collect::<Vec<_>>();
+    assert_eq!(pairs, expected);
+    let range = b"key02500".to_vec()..=b"key07499".to_vec();
+    assert_eq!(snapshot.scan(range.clone())?.count(), 5000);
+    assert_eq!(snapshot.scan_prefix(b"key05")?.count(), 1000);
+
+    assert_eq!(store.get_str("")?, Some("empty".to_owned()));
+    let pairs = store.scan(range)?.collect::<Result<Vec<_>>>()?;
+    let expected = (2500..=7499)
+        .filter(|key_id| key_id % 3 != 1)
+        .map(|key_id| {
+            let value = if key_id % 3 == 0 { "new" } else { "old" };
+            (key(key_id).into_bytes(), value.as_bytes().to_vec())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(pairs, expected);
+    assert_eq!(store.scan_prefix(b"key05")?.count(), 667);
+    assert_eq!(store.keys()?.count(), 6668);
+    Ok(())
+}
+
+// The files a snapshot reads from should outlive compaction until it is dropped.
+#[test]
+fn snapshot_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set_str(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    let snapshot = store.snapshot();
+    let pinned = kvs_files(temp_dir.path());
+
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set_str(format!("key{key_id}"), format!("{iter}"))?;
+        }
+    }
+    for _ in 0..100 {
+        if !hint_files(temp_dir.path()).is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(
+        !hint_files(temp_dir.path()).is_empty(),
+        "No compaction detected"
+    );
+    assert!(pinned.iter().all(|path| path.exists()));
+    for key_id in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{key_id}").as_bytes())?,
+            Some(format!("value{key_id}").into_bytes())
+        );
+    }
+    assert_eq!(snapshot.scan_prefix(b"key")?.count(), 100);
+
+    drop(snapshot);
+    assert_eq!(store.get_str("key0")?, Some("99".to_owned()));
+    // compaction may still be swapping its file in
+    for _ in 0..100 {
+        if pinned.iter().all(|path| !path.exists()) {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Pinned files not removed");
+}
+
+fn check_transaction(engine: impl KvsEngine) -> Result<()> {
+    engine.set_str("a", "100")?;
+    engine.set_str("b", "100")?;
+    let balance = |value: Option<Vec<u8>>| -> i64 {
+        String::from_utf8(value.unwrap()).unwrap().parse().unwrap()
+    };
+    // concurrent transfers keep the total
+    let handles = (0..4)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                let (from, to) = if t % 2 == 0 { ("a", "b") } else { ("b", "a") };
+                for _ in 0..50 {
+                    engine.transaction(|txn| {
+                        let from_balance = balance(txn.get(from.as_bytes())?);
+                        let to_balance = balance(txn.get(to.as_bytes())?);
+                        txn.set(from.into(), (from_balance - 1).to_string().into_bytes());
+                        txn.set(to.into(), (to_balance + 1).to_string().into_bytes());
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let total = balance(engine.get(b"a")?) + balance(engine.get(b"b")?);
+    assert_eq!(total, 200);
+
+    // an error aborts the transaction
+    let ret = engine.transaction(|txn| {
+        txn.set(b"c".to_vec(), b"value".to_vec());
+        assert_eq!(txn.get(b"c")?, Some(b"value".to_vec()));
+        txn.remove(b"missing")
+    });
+    assert!(matches!(ret, Err(KvsError::KeyNotFound { .. })));
+    assert_eq!(engine.get_str("c")?, None);
+    Ok(())
+}
+
+// Transactions should be serializable, and retried on conflict.
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Values should be compressed with the codec of the options,
+// and records of any codec should be readable whatever the current one is.
+#[test]
+fn compression() -> Result<()> {
+    let dir_size = |dir: &Path| -> u64 {
+        kvs_files(dir)
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    let value = |i: usize| format!("value{i}").repeat(100).into_bytes();
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain = KvStore::open(plain_dir.path())?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compression(Compression::Lz4),
+    )?;
+    for i in 0..100 {
+        plain.set(format!("key{i}").into_bytes(), value(i))?;
+        store.set(format!("key{i}").into_bytes(), value(i))?;
+    }
+    // too short to be made any smaller
+    store.set_str("short", "v")?;
+    assert!(dir_size(temp_dir.path()) * 4 < dir_size(plain_dir.path()));
+    let stats = store.compression_stats();
+    assert_eq!(stats.compressed_logs, 100);
+    assert_eq!(stats.uncompressed_logs, 1);
+    assert!(stats.ratio() < 0.25);
+    assert_eq!(plain.compression_stats().compressed_logs, 0);
+    assert_eq!(plain.compression_stats().ratio(), 1.0);
+    drop(store);
+
+    // lz4 and snappy records side by side
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compression(Compression::Snappy),
+    )?;
+    let mut batch = WriteBatch::new();
+    for i in 100..200 {
+        batch.set(format!("key{i}").into_bytes(), value(i));
+    }
+    store.write_batch(batch)?;
+    assert_eq!(store.compression_stats().compressed_logs, 100);
+    assert_eq!(store.get(b"key1")?, Some(value(1)));
+    assert_eq!(store.get(b"key101")?, Some(value(101)));
+    drop(store);
+
+    // and uncompressed ones
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("key0", "value")?;
+    for i in 1..200 {
+        assert_eq!(store.get(format!("key{i}").as_bytes())?, Some(value(i)));
+    }
+    assert_eq!(store.get_str("key0")?, Some("value".to_owned()));
+    assert_eq!(store.get_str("short")?, Some("v".to_owned()));
+    drop(store);
+    remove_hint_files(temp_dir.path());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key199")?, Some(value(199)));
+    assert_eq!(store.scan(..)?.count(), 201);
+    Ok(())
+}
+
+#[test]
+fn parse_compression() {
+    assert_eq!("none".parse::<Compression>().unwrap(), Compression::None);
+    assert_eq!("lz4".parse::<Compression>().unwrap(), Compression::Lz4);
+    assert_eq!(
+        "snappy".parse::<Compression>().unwrap(),
+        Compression::Snappy
+    );
+    assert!("zstd".parse::<Compression>().is_err());
+}
+
+// Records should be encrypted with the given key, unreadable without it,
+// and moved to a new key by compaction, and values in blob files by blob GC.
+#[test]
+fn encryption() -> Result<()> {
+    let contains_secret = |dir: &Path| {
+        WalkDir::new(dir).into_iter().any(|entry| {
+            let entry = entry.unwrap();
+            entry.file_type().is_file()
+                && fs::read(entry.path())
+                    .unwrap()
+                    .windows(6)
+                    .any(|w| w == b"secret")
+        })
+    };
+    let key1 = EncryptionKey::new([1; 32]);
+    let key2 = EncryptionKey::from_bytes(format!("{}\n", "02".repeat(32)).as_bytes())?;
+    assert_ne!(key1.id(), key2.id());
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // records written before the key was given stay readable
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_str("plain", "value")?;
+    drop(store);
+    let with_key = |key: &EncryptionKey| {
+        KvStoreOptions::new()
+            .encryption_key(key.clone())
+            .blob_threshold(1024)
+    };
+    let large = || "secret value".repeat(100);
+    let store = KvStore::open_with(temp_dir.path(), with_key(&key1))?;
+    assert_eq!(store.get_str("plain")?, Some("value".to_owned()));
+    store.set_str("secret1", "secret value")?;
+    store.set_str("secret2", "secret value")?;
+    store.remove_str("secret2")?;
+    store.set_str("blob", large())?;
+    assert!(!blob_files(temp_dir.path()).is_empty());
+    assert!(!contains_secret(temp_dir.path()));
+    drop(store);
+    assert!(!contains_secret(temp_dir.path()));
+
+    for options in [KvStoreOptions::new(), with_key(&key2)] {
+        match KvStore::open_with(temp_dir.path(), options.clone()) {
+            Err(KvsError::UnknownKey { key_id }) => assert_eq!(key_id, key1.id()),
+            _ => panic!("opened with the wrong key"),
+        }
+        // without hint files, the logs are replayed
+        remove_hint_files(temp_dir.path());
+        match KvStore::open_with(temp_dir.path(), options.read_only(true)) {
+            Err(KvsError::UnknownKey { key_id }) => assert_eq!(key_id, key1.id()),
+            _ => panic!("opened with the wrong key"),
+        }
+    }
+
+    // rotate to key2, compaction and blob GC on drop rewrite everything with it
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        with_key(&key2).decryption_key(key1.clone()),
+    )?;
+    assert_eq!(store.get_str("secret1")?, Some("secret value".to_owned()));
+    store.set_str("secret3", "secret value")?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), with_key(&key2))?;
+    assert_eq!(store.get_str("plain")?, Some("value".to_owned()));
+    assert_eq!(store.get_str("secret1")?, Some("secret value".to_owned()));
+    assert_eq!(store.get_str("secret2")?, None);
+    assert_eq!(store.get_str("secret3")?, Some("secret value".to_owned()));
+    assert_eq!(store.get_str("blob")?, Some(large()));
+    drop(store);
+    assert!(!contains_secret(temp_dir.path()));
+    Ok(())
+}
+
+// Logs rewritten by compaction with a new key or codec change in length,
+// which should be accounted for when they are overwritten or removed afterwards.
+#[test]
+fn remove_after_rewriting_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set_str(format!("key{key_id}"), "value")?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions::new()
+        .encryption_key(EncryptionKey::new([1; 32]))
+        .compression(Compression::Lz4)
+        .compaction_threshold(1);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_str("key0", "value")?;
+    for _ in 0..100 {
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.stats()?.compactions, 1);
+    for key_id in 0..100 {
+        store.remove_str(format!("key{key_id}"))?;
+    }
+    assert_eq!(store.stats()?.keys, 0);
+    store.set_str("key", "value")?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.keys()?.collect::<Result<Vec<_>>>()?, [b"key"]);
+    Ok(())
+}
+
+#[test]
+fn parse_encryption_key() {
+    let hex = "0123456789abcdef".repeat(4);
+    let key = hex.parse::<EncryptionKey>().unwrap();
+    assert_eq!(
+        EncryptionKey::from_bytes(hex.as_bytes()).unwrap().id(),
+        key.id()
+    );
+    assert!(!format!("{key:?}").contains(&hex));
+    assert!(hex[1..].parse::<EncryptionKey>().is_err());
+    assert!("xy".repeat(32).parse::<EncryptionKey>().is_err());
+    assert!(EncryptionKey::from_bytes(&[0; 31]).is_err());
+}
+
+fn blob_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("blob".as_ref()))
+        .collect()
+}
+
+// Large values should be kept in blob files, which compaction does not copy,
+// and their garbage should be collected apart from compaction.
+#[test]
+fn blob_files_for_large_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // blob GC may remove a file after it is listed
+    let dir_size = |files: Vec<PathBuf>| -> u64 {
+        files
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    let options = KvStoreOptions::new()
+        .blob_threshold(1024)
+        .blob_gc_threshold(64 * 1024)
+        .max_segment_size(32 * 1024);
+    let value = |i: usize, round: usize| vec![(i + round) as u8; 2048 + i];
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_str("small", "value")?;
+    for i in 0..50 {
+        store.set(format!("key{i}").into_bytes(), value(i, 0))?;
+    }
+    assert!(dir_size(kvs_files(temp_dir.path())) < 50 * 100);
+    assert!(dir_size(blob_files(temp_dir.path())) > 50 * 2048);
+
+    // a snapshot reads the values of blob files collected since
+    let snapshot = store.snapshot();
+    for round in 1..10 {
+        let mut batch = WriteBatch::new();
+        for i in 0..50 {
+            batch.set(format!("key{i}").into_bytes(), value(i, round));
+        }
+        store.write_batch(batch)?;
+    }
+    store.remove(b"key0")?;
+    for i in 1..50 {
+        assert_eq!(store.get(format!("key{i}").as_bytes())?, Some(value(i, 9)));
+        assert_eq!(
+            snapshot.get(format!("key{i}").as_bytes())?,
+            Some(value(i, 0))
+        );
+    }
+    // the garbage of 10 rounds is collected once the snapshot no longer pins it,
+    // blob GC runs in the background
+    drop(snapshot);
+    for _ in 0..100 {
+        if dir_size(blob_files(temp_dir.path())) < 4 * 50 * 2100 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(dir_size(blob_files(temp_dir.path())) < 4 * 50 * 2100);
+    drop(store);
+
+    // with hint files, without them, and without the option
+    for options in [options, KvStoreOptions::new()] {
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get(b"key0")?, None);
+        for i in 1..50 {
+            assert_eq!(store.get(format!("key{i}").as_bytes())?, Some(value(i, 9)));
+        }
+        assert_eq!(store.get_str("small")?, Some("value".to_owned()));
+        drop(store);
+        remove_hint_files(temp_dir.path());
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.scan(..)?.count(), 50);
+    }
+    // the files of older values are all gone
+    assert!(dir_size(blob_files(temp_dir.path())) < 2 * 50 * 2100);
+    Ok(())
+}
+
+// A key whose value a crash has cut off the end of its blob file should be dropped on open,
+// rather than counted as live past the end of the file.
+#[test]
+fn blob_lost_in_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .blob_threshold(1024)
+        .blob_gc_threshold(1);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set(b"key1".to_vec(), vec![1; 2048])?;
+    store.set(b"key2".to_vec(), vec![2; 2048])?;
+    drop(store);
+    let blob = temp_dir.path().join("1.blob");
+    let len = fs::metadata(&blob)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&blob)?
+        .set_len(len - 100)?;
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get(b"key1")?, Some(vec![1; 2048]));
+    assert_eq!(store.get(b"key2")?, None);
+    assert_eq!(store.stats()?.keys, 1);
+    // blob GC takes the file with the lost value back
+    store.set(b"key3".to_vec(), vec![3; 2048])?;
+    store.set_str("key4", "value")?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get(b"key1")?, Some(vec![1; 2048]));
+    assert_eq!(store.get(b"key2")?, None);
+    assert_eq!(store.get(b"key3")?, Some(vec![3; 2048]));
+    Ok(())
+}
+
+// The values appended to blob files by a batch which fails partway should be garbage,
+// so blob GC takes their space back.
+#[test]
+fn blobs_of_failed_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .blob_threshold(1024)
+        .blob_gc_threshold(1)
+        .max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    // the second value of the batch goes to 2.blob, which cannot be created as a file
+    fs::create_dir(temp_dir.path().join("2.blob"))?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), vec![1; 2048]);
+    batch.set(b"key2".to_vec(), vec![2; 2048]);
+    assert!(store.write_batch(batch).is_err());
+    assert_eq!(store.get(b"key1")?, None);
+    assert!(temp_dir.path().join("1.blob").exists());
+
+    fs::remove_dir(temp_dir.path().join("2.blob"))?;
+    store.set_str("key", "value")?;
+    for _ in 0..100 {
+        if !temp_dir.path().join("1.blob").exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!temp_dir.path().join("1.blob").exists());
+    Ok(())
+}
+
+// A store dropped while blob GC is running should be closed once the drop returns,
+// so it can be opened again right away.
+#[test]
+fn drop_while_collecting_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .blob_threshold(1024)
+        .blob_gc_threshold(4 * 1024)
+        .max_segment_size(8 * 1024);
+    let value = |key_id: usize, round: usize| vec![(key_id + round) as u8; 2048];
+    for round in 0..20 {
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for key_id in 0..10 {
+            if round > 0 {
+                assert_eq!(
+                    store.get(format!("key{key_id}").as_bytes())?,
+                    Some(value(key_id, round - 1))
+                );
+            }
+            store.set(format!("key{key_id}").into_bytes(), value(key_id, round))?;
+        }
+        drop(store);
+    }
+    Ok(())
+}
+
+// Values should be served by the cache once read or written,
+// and never after they are overwritten, removed or expired.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.cache_stats(), None);
+    drop(store);
+
+    let options = KvStoreOptions::new()
+        .value_cache(1024)
+        .blob_threshold(512)
+        .compaction_threshold(1)
+        .sweep_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set_str("key1", "value1")?;
+    assert_eq!(store.get_str("key1")?, Some("value1".to_owned()));
+    store.set_str("key1", "value2")?;
+    assert_eq!(store.get_str("key1")?, Some("value2".to_owned()));
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (2, 0));
+    assert_eq!((stats.entries, stats.size), (1, 10));
+
+    store.remove(b"key1")?;
+    assert_eq!(store.get_str("key1")?, None);
+    assert_eq!(store.cache_stats().unwrap().entries, 0);
+
+    // a value in a blob file is cached once read
+    store.set(b"blob".to_vec(), vec![1; 600])?;
+    assert_eq!(store.get(b"blob")?, Some(vec![1; 600]));
+    assert_eq!(store.get(b"blob")?, Some(vec![1; 600]));
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (3, 1));
+
+    // the least recently used values are evicted, a value larger than the cache is never cached
+    for key_id in 0..100 {
+        store.set_str(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    store.set(b"huge".to_vec(), vec![2; 2048])?;
+    let stats = store.cache_stats().unwrap();
+    assert!(stats.size <= 1024);
+    assert!(stats.entries < 100);
+    assert_eq!(store.get_str("key99")?, Some("value99".to_owned()));
+    assert_eq!(store.get(b"huge")?, Some(vec![2; 2048]));
+    assert_eq!(store.get_str("key0")?, Some("value0".to_owned()));
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (4, 3));
+
+    // expired keys dropped by compaction are dropped from the cache
+    store.set_str("key", "value")?;
+    store.set_with_ttl(
+        b"ttl".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get(b"ttl")?, None);
+    let entries = store.cache_stats().unwrap().entries;
+    for _ in 0..100 {
+        store.set_str("key", "value")?;
+        thread::sleep(Duration::from_millis(10));
+        if store.cache_stats().unwrap().entries < entries {
+            break;
+        }
+    }
+    assert!(store.cache_stats().unwrap().entries < entries);
+    Ok(())
+}
+
+fn check_stats(engine: &impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.stats()?.keys, 0);
+    for key_id in 0..10 {
+        engine.set_str(format!("key{key_id}"), "value")?;
+    }
+    engine.remove_str("key0")?;
+    engine.set_with_ttl(b"ttl".to_vec(), b"value".to_vec(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 9);
+    assert!(stats.live_bytes >= 9 * "key1value".len() as u64);
+    assert!(stats.segment_count() >= 1);
+    assert!(stats.to_string().contains("keys: 9\n"));
+    Ok(())
+}
+
+// Stats should count the live keys and tell the live bytes from the dead ones.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_stats(&SledKvsEngine::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sweep_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    check_stats(&store)?;
+    let stats = store.stats()?;
+    assert_eq!((stats.compactions, stats.last_compaction), (0, None));
+    let size = stats.segment_sizes.iter().sum::<u64>();
+    assert_eq!(stats.live_bytes + stats.dead_bytes, size);
+    assert!(stats.dead_bytes > 0);
+
+    // compaction drops the dead bytes
+    drop(store);
+    let options = KvStoreOptions::new().compaction_threshold(1);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let before = store.stats()?;
+    assert_eq!(before.dead_bytes, 0);
+    for _ in 0..100 {
+        store.set_str("key1", "value")?;
+        thread::sleep(Duration::from_millis(10));
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+    }
+    let stats = store.stats()?;
+    assert!(stats.compactions > 0);
+    assert!(stats.last_compaction.is_some());
+    assert_eq!(stats.keys, 9);
+
+    let stats =
+        KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?.stats()?;
+    assert_eq!(stats.keys, 9);
+    assert_eq!(stats.compactions, 0);
+    Ok(())
+}
+
+// The sled counts should follow every kind of write and be the same after reopening.
+#[test]
+fn sled_stats_counts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set_str("key1", "value1")?;
+    engine.set_str("key1", "value")?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key2".to_vec(), b"value2".to_vec())
+        .set(b"key3".to_vec(), b"value3".to_vec())
+        .remove(b"key3".to_vec())
+        .remove(b"missing".to_vec());
+    engine.write_batch(batch)?;
+    engine
+        .compare_and_swap(b"key4".to_vec(), None, Some(b"value4".to_vec()))?
+        .unwrap();
+    engine
+        .compare_and_swap(b"key2".to_vec(), Some(b"value2".to_vec()), None)?
+        .unwrap();
+    assert!(engine.remove_str("key2").is_err());
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(
+        stats.live_bytes,
+        ("key1value".len() + "key4value4".len()) as u64
+    );
+    drop(engine);
+
+    // sled lets go of its lock on the db from a background thread
+    let mut reopened = SledKvsEngine::open(temp_dir.path());
+    for _ in 0..100 {
+        if reopened.is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+        reopened = SledKvsEngine::open(temp_dir.path());
+    }
+    let reopened = reopened?.stats()?;
+    assert_eq!(
+        (reopened.keys, reopened.live_bytes),
+        (stats.keys, stats.live_bytes)
+    );
+    Ok(())
+}
+
+fn check_iter(engine: &impl KvsEngine) -> Result<()> {
+    assert_eq!(engine.iter()?.count(), 0);
+    for key in ["c", "a", "b", "d"] {
+        engine.set_str(key.to_owned(), format!("value_{key}"))?;
+    }
+    engine.remove_str("d")?;
+    engine.set_with_ttl(b"ttl".to_vec(), b"value".to_vec(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    let pairs = engine.iter()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        ["a", "b", "c"].map(|key| (key.as_bytes().to_vec(), format!("value_{key}").into_bytes()))
+    );
+    let keys = engine.keys()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, [b"a", b"b", b"c"]);
+    Ok(())
+}
+
+// Iteration should visit every live key in order, with or without the values.
+#[test]
+fn iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_iter(&SledKvsEngine::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_iter(&store)?;
+
+    // a corrupted value is an error of its own, and the iteration goes on
+    store.set_str("a", "value_a")?;
+    flip_byte_in_kvs_file(temp_dir.path(), 3);
+    let pairs = store.iter()?.collect::<Vec<_>>();
+    assert_eq!(pairs.len(), 3);
+    assert!(matches!(pairs[0], Err(KvsError::Corrupted { .. })));
+    assert!(pairs[1..].iter().all(|pair| pair.is_ok()));
+    assert_eq!(store.keys()?.count(), 3);
+    Ok(())
+}
+
+// A checkpoint should be an openable copy of the store as of a write, taken while writes go on.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(1024)
+        .compaction_threshold(2048)
+        .blob_threshold(256);
+    let store = KvStore::open_with(temp_dir.path().join("db"), options.clone())?;
+    let value = |key_id: usize| format!("value{key_id}").repeat(key_id % 50);
+    for key_id in 0..100 {
+        store.set_str(format!("key{key_id}"), value(key_id))?;
+    }
+    let dests = thread::scope(|s| -> Result<Vec<PathBuf>> {
+        let writer = s.spawn(|| -> Result<()> {
+            for key_id in 100..1000 {
+                store.set_str(format!("key{key_id}"), value(key_id))?;
+            }
+            Ok(())
+        });
+        let mut dests = Vec::new();
+        for i in 0..5 {
+            let dest = temp_dir.path().join(format!("checkpoint{i}"));
+            store.checkpoint(&dest)?;
+            dests.push(dest);
+        }
+        writer.join().unwrap()?;
+        Ok(dests)
+    })?;
+    assert!(matches!(
+        store.checkpoint(&dests[0]),
+        Err(KvsError::InvalidOption(_))
+    ));
+
+    // the keys are written in order, so a checkpoint holds the first ones of them
+    for dest in dests {
+        let copy = KvStore::open_with(&dest, options.clone())?;
+        let count = copy.keys()?.count();
+        assert!(count >= 100);
+        for key_id in 0..1000 {
+            let expected = (key_id < count).then(|| value(key_id));
+            assert_eq!(copy.get_str(format!("key{key_id}"))?, expected);
+        }
+    }
+
+    let dest = temp_dir.path().join("checkpoint_read_only");
+    drop(store);
+    KvStore::open_with(temp_dir.path().join("db"), options.clone().read_only(true))?
+        .checkpoint(&dest)?;
+    let copy = KvStore::open_with(&dest, options)?;
+    assert_eq!(copy.get_str("key999")?, Some(value(999)));
+    Ok(())
+}
+
+// A sled checkpoint should be an openable copy of the db, TTLs included.
+#[test]
+fn checkpoint_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path().join("db"))?;
+    for key_id in 0..100 {
+        engine.set_str(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    engine.set_with_ttl(
+        b"ttl".to_vec(),
+        b"value".to_vec(),
+        Duration::from_millis(100),
+    )?;
+    let dest = temp_dir.path().join("checkpoint");
+    engine.checkpoint(&dest)?;
+    assert!(matches!(
+        engine.checkpoint(&dest),
+        Err(KvsError::InvalidOption(_))
+    ));
+
+    let copy = SledKvsEngine::open(&dest)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            copy.get_str(format!("key{key_id}"))?,
+            Some(format!("value{key_id}"))
+        );
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(copy.get(b"ttl")?, None);
+    Ok(())
+}
+
+// An export should be imported into either engine as it was, bytes which are not UTF-8 included,
+// and a dry run should report the keys which would be overwritten without writing them.
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    for key_id in 0..100 {
+        store.set_str(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    store.set(vec![0xff, 0], vec![0x80, b'\n', b'"'])?;
+    let mut export = Vec::new();
+    assert_eq!(jsonl::export(&store, &mut export)?, 101);
+    let lines = String::from_utf8(export.clone()).unwrap();
+    assert_eq!(lines.lines().count(), 101);
+    assert_eq!(
+        lines.lines().next(),
+        Some(r#"{"key":"key0","value":"value0"}"#)
+    );
+    assert_eq!(
+        lines.lines().last(),
+        Some(r#"{"key":[255,0],"value":[128,10,34]}"#)
+    );
+
+    let sled = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+    sled.set_str("key1", "value1")?;
+    sled.set_str("key2", "other")?;
+    let conflicts = ImportReport {
+        pairs: 101,
+        new: 99,
+        unchanged: 1,
+        conflicts: vec![b"key2".to_vec()],
+        repeated: vec![],
+    };
+    let report = jsonl::import(&sled, &export[..], ImportOptions::new().dry_run(true))?;
+    assert_eq!(report, conflicts);
+    assert_eq!(sled.keys()?.count(), 2);
+    assert_eq!(sled.get_str("key2")?, Some("other".to_owned()));
+
+    let report = jsonl::import(&sled, &export[..], ImportOptions::new().batch_size(16))?;
+    assert_eq!(report, conflicts);
+    let pairs = sled.iter()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs, store.iter()?.collect::<Result<Vec<_>>>()?);
+
+    // and back into a new kvs store, one pair at a time
+    let copy = KvStore::open(temp_dir.path().join("copy"))?;
+    let mut export = Vec::new();
+    jsonl::export(&sled, &mut export)?;
+    let report = jsonl::import(&copy, &export[..], ImportOptions::new())?;
+    assert_eq!((report.new, report.unchanged), (101, 0));
+    assert_eq!(copy.get(&[0xff, 0])?, Some(vec![0x80, b'\n', b'"']));
+    assert_eq!(copy.keys()?.count(), 101);
+
+    // a line which is not a pair fails the import with its line number
+    let input = "\n{\"key\":\"a\",\"value\":\"b\"}\nnot json\n";
+    assert!(matches!(
+        jsonl::import(&copy, input.as_bytes(), ImportOptions::new()),
+        Err(KvsError::Import { line: 3, .. })
+    ));
+    assert_eq!(copy.get_str("a")?, Some("b".to_owned()));
+
+    // a repeated key is only checked the first time it is read, and its last value is written,
+    // the same in a dry run, one pair at a time and in batches
+    let input = [
+        r#"{"key":"new","value":"1"}"#,
+        r#"{"key":"a","value":"b"}"#,
+        r#"{"key":"new","value":"2"}"#,
+        r#"{"key":"a","value":"c"}"#,
+    ]
+    .join("\n");
+    let repeats = ImportReport {
+        pairs: 4,
+        new: 1,
+        unchanged: 1,
+        conflicts: vec![],
+        repeated: vec![b"new".to_vec(), b"a".to_vec()],
+    };
+    let written = [
+        (None, "b"),
+        (Some("2".to_owned()), "c"),
+        (Some("2".to_owned()), "c"),
+    ];
+    let options = [
+        ImportOptions::new().dry_run(true),
+        ImportOptions::new(),
+        ImportOptions::new().batch_size(16),
+    ];
+    for (i, (options, (new, a))) in options.into_iter().zip(written).enumerate() {
+        let copy = KvStore::open(temp_dir.path().join(format!("repeats{i}")))?;
+        copy.set_str("a", "b")?;
+        assert_eq!(jsonl::import(&copy, input.as_bytes(), options)?, repeats);
+        assert_eq!(copy.get_str("new")?, new);
+        assert_eq!(copy.get_str("a")?, Some(a.to_owned()));
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use kvs::{
+    cli::{Request, Response},
+    client::KvsClient,
+    engine::sled::SledKvsEngine,
+    server::KvsServer,
+    KvStore, KvsEngine, Result,
+};
+use slog::{o, Discard, Logger};
+use std::{thread, time::Duration};
+use tempfile::TempDir;
+
+// run a server in the background, it lives as long as the test process
+fn spawn_server(engine: impl KvsEngine, addr: &'static str) {
+    thread::spawn(move || {
+        let logger = Logger::root(Discard, o!());
+        KvsServer::new(engine, &logger).run(addr)
+    });
+    thread::sleep(Duration::from_millis(200));
+}
+
+fn set(client: &mut KvsClient, key: &str, value: &str) -> Result<()> {
+    let request = Request::Set {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+        ttl: None,
+    };
+    assert!(matches!(
+        client.send_request(&request)?,
+        Response::Set(Ok(()))
+    ));
+    Ok(())
+}
+
+fn get(client: &mut KvsClient, key: &str) -> Result<Option<Vec<u8>>> {
+    let request = Request::Get {
+        key: key.as_bytes().to_vec(),
+    };
+    match client.send_request(&request)? {
+        Response::Get(Ok(value)) => Ok(value),
+        response => panic!("unexpected response {response:?}"),
+    }
+}
+
+fn check_transaction(addr: &str) -> Result<()> {
+    let mut client1 = KvsClient::connect(addr)?;
+    let mut client2 = KvsClient::connect(addr)?;
+    set(&mut client1, "key1", "value1")?;
+
+    // writes in a transaction are only seen after commit
+    assert!(matches!(
+        client1.send_request(&Request::Begin)?,
+        Response::Begin(Ok(()))
+    ));
+    assert_eq!(get(&mut client1, "key1")?, Some(b"value1".to_vec()));
+    set(&mut client1, "key1", "value2")?;
+    assert_eq!(get(&mut client1, "key1")?, Some(b"value2".to_vec()));
+    assert_eq!(get(&mut client2, "key1")?, Some(b"value1".to_vec()));
+    assert!(matches!(
+        client1.send_request(&Request::Commit)?,
+        Response::Commit(Ok(true))
+    ));
+    assert_eq!(get(&mut client2, "key1")?, Some(b"value2".to_vec()));
+
+    // a key read by the transaction is written by someone else
+    client1.send_request(&Request::Begin)?;
+    assert_eq!(get(&mut client1, "key1")?, Some(b"value2".to_vec()));
+    set(&mut client1, "key2", "value3")?;
+    set(&mut client2, "key1", "value4")?;
+    assert!(matches!(
+        client1.send_request(&Request::Commit)?,
+        Response::Commit(Ok(false))
+    ));
+    assert_eq!(get(&mut client1, "key2")?, None);
+
+    // nothing is written on abort
+    client1.send_request(&Request::Begin)?;
+    set(&mut client1, "key2", "value5")?;
+    assert!(matches!(
+        client1.send_request(&Request::Abort)?,
+        Response::Abort(Ok(()))
+    ));
+    assert_eq!(get(&mut client1, "key2")?, None);
+    assert!(matches!(
+        client1.send_request(&Request::Commit)?,
+        Response::Commit(Err(_))
+    ));
+    Ok(())
+}
+
+// A connection should run a transaction between `Begin` and `Commit` or `Abort`.
+#[test]
+fn transaction_over_the_wire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    spawn_server(KvStore::open(temp_dir.path())?, "127.0.0.1:4010");
+    check_transaction("127.0.0.1:4010")?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    spawn_server(SledKvsEngine::open(temp_dir.path())?, "127.0.0.1:4011");
+    check_transaction("127.0.0.1:4011")
+}